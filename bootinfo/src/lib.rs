@@ -0,0 +1,291 @@
+// Data handed from the bootloader to the kernel.
+//
+// Both sides depend on this crate so that the layout of the boot handoff is
+// defined in one place. Bump `BOOT_INFO_VERSION` whenever `BootInfo` or any
+// of the structures it contains changes.
+#![no_std]
+
+use uefi::proto::console::gop::ModeInfo;
+use uefi::table::boot::MemoryDescriptor;
+
+// "RIKANBI\0" in little endian
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"RIKANBI\0");
+pub const BOOT_INFO_VERSION: u32 = 9;
+pub const BOOT_MODULE_NAME_LEN: usize = 64;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct FrameBufferConfig {
+    pub frame_buffer: u64,
+    pub frame_buffer_size: u64,
+    pub mode_info: ModeInfo,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryMapConfig {
+    pub descriptors: u64,
+    pub map_size: u64,
+    pub descriptor_size: u64,
+    pub descriptor_version: u32,
+}
+
+impl MemoryMapConfig {
+    pub fn len(&self) -> usize {
+        if self.descriptor_size == 0 {
+            return 0;
+        }
+        (self.map_size / self.descriptor_size) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&MemoryDescriptor> {
+        if index >= self.len() {
+            return None;
+        }
+        // The firmware may use a larger descriptor than MemoryDescriptor,
+        // so always step through the array with descriptor_size.
+        let addr = self.descriptors + index as u64 * self.descriptor_size;
+        Some(unsafe { &*(addr as *const MemoryDescriptor) })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MemoryDescriptor> {
+        (0..self.len()).filter_map(move |i| self.get(i))
+    }
+}
+
+// A file loaded by the loader next to the kernel (initrd, fonts, programs)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BootModule {
+    // NUL padded file name
+    pub name: [u8; BOOT_MODULE_NAME_LEN],
+    pub address: u64,
+    pub size: u64,
+}
+
+impl BootModule {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(BOOT_MODULE_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    // # Safety
+    //
+    // The module memory must still be mapped and not reused.
+    pub unsafe fn data(&self) -> &[u8] {
+        core::slice::from_raw_parts(self.address as *const u8, self.size as usize)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BootModules {
+    pub modules: u64,
+    pub count: u64,
+}
+
+impl BootModules {
+    pub fn as_slice(&self) -> &[BootModule] {
+        if self.count == 0 {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(self.modules as *const BootModule, self.count as usize)
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&BootModule> {
+        self.as_slice().iter().find(|module| module.name() == name)
+    }
+}
+
+// TSC values recorded by the loader at the end of each boot stage
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BootTimestamps {
+    // TSC ticks per second as measured by the loader, or 0 if unknown
+    pub tsc_frequency: u64,
+    pub loader_start: u64,
+    pub allocator_init: u64,
+    pub boot_menu: u64,
+    pub mmap_dump: u64,
+    pub kernel_read: u64,
+    pub kernel_verify: u64,
+    pub kernel_load: u64,
+    pub gop_open: u64,
+    pub exit_boot_services: u64,
+}
+
+impl BootTimestamps {
+    // Stage names and their end timestamps, in boot order
+    pub fn stages(&self) -> [(&'static str, u64); 9] {
+        [
+            ("loader start", self.loader_start),
+            ("allocator init", self.allocator_init),
+            ("boot menu", self.boot_menu),
+            ("mmap dump", self.mmap_dump),
+            ("kernel read", self.kernel_read),
+            ("kernel verify", self.kernel_verify),
+            ("ELF load", self.kernel_load),
+            ("GOP open", self.gop_open),
+            ("exit boot services", self.exit_boot_services),
+        ]
+    }
+
+    // Converts a number of TSC ticks to microseconds
+    pub fn ticks_to_us(&self, ticks: u64) -> Option<u64> {
+        if self.tsc_frequency == 0 {
+            return None;
+        }
+        Some((ticks as u128 * 1_000_000 / self.tsc_frequency as u128) as u64)
+    }
+}
+
+// Copies of the kernel's `.symtab` and `.strtab` sections. Symbol values are
+// link-time addresses, see `BootInfo::kernel_slide`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct KernelSymbols {
+    pub symtab: u64,
+    pub symtab_size: u64,
+    pub strtab: u64,
+    pub strtab_size: u64,
+}
+
+impl KernelSymbols {
+    pub fn is_empty(&self) -> bool {
+        self.symtab_size == 0 || self.strtab_size == 0
+    }
+
+    // Raw `Elf64_Sym` entries
+    pub fn symtab(&self) -> &[u8] {
+        if self.is_empty() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.symtab as *const u8, self.symtab_size as usize) }
+    }
+
+    pub fn strtab(&self) -> &[u8] {
+        if self.is_empty() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.strtab as *const u8, self.strtab_size as usize) }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    pub size: u32,
+    pub frame_buffer: FrameBufferConfig,
+    pub memory_map: MemoryMapConfig,
+    // Physical address of the ACPI RSDP, or 0 if the firmware has none
+    pub acpi_rsdp: u64,
+    // Physical address of the SMBIOS 2.x entry point, or 0
+    pub smbios: u64,
+    // Physical address of the SMBIOS 3.x entry point, or 0
+    pub smbios3: u64,
+    pub modules: BootModules,
+    // NUL-terminated kernel command line, or 0 if there is none
+    pub cmdline: u64,
+    // Lowest virtual address of the kernel image (page aligned)
+    pub kernel_virtual_base: u64,
+    // Physical address `kernel_virtual_base` is mapped to
+    pub kernel_physical_base: u64,
+    // Size of the kernel image in bytes (page aligned)
+    pub kernel_size: u64,
+    // Difference between the addresses the kernel runs at and the addresses
+    // it was linked at. Add it to ELF symbol values to get runtime addresses.
+    pub kernel_slide: u64,
+    pub kernel_symbols: KernelSymbols,
+    // The kernel starts on a stack of `kernel_stack_size` bytes ending here
+    pub kernel_stack_top: u64,
+    pub kernel_stack_size: u64,
+    pub timestamps: BootTimestamps,
+    // Physical address of the UEFI system table, for runtime services. The
+    // regions they use are the memory map entries with the RUNTIME attribute.
+    pub uefi_system_table: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BootInfoError {
+    BadMagic(u64),
+    VersionMismatch { expected: u32, found: u32 },
+    SizeMismatch { expected: u32, found: u32 },
+}
+
+impl BootInfo {
+    pub fn new(frame_buffer: FrameBufferConfig, memory_map: MemoryMapConfig) -> Self {
+        BootInfo {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: core::mem::size_of::<BootInfo>() as u32,
+            frame_buffer,
+            memory_map,
+            acpi_rsdp: 0,
+            smbios: 0,
+            smbios3: 0,
+            modules: BootModules {
+                modules: 0,
+                count: 0,
+            },
+            cmdline: 0,
+            kernel_virtual_base: 0,
+            kernel_physical_base: 0,
+            kernel_size: 0,
+            kernel_slide: 0,
+            kernel_symbols: KernelSymbols {
+                symtab: 0,
+                symtab_size: 0,
+                strtab: 0,
+                strtab_size: 0,
+            },
+            kernel_stack_top: 0,
+            kernel_stack_size: 0,
+            timestamps: BootTimestamps::default(),
+            uefi_system_table: 0,
+        }
+    }
+
+    // The command line lives in loader memory, not in `BootInfo` itself
+    pub fn cmdline(&self) -> &'static str {
+        if self.cmdline == 0 {
+            return "";
+        }
+        let cmdline =
+            unsafe { core::ffi::CStr::from_ptr(self.cmdline as *const core::ffi::c_char) };
+        cmdline.to_str().unwrap_or("")
+    }
+
+    // Checks that the loader and the kernel were built against the same
+    // version of this crate.
+    pub fn validate(&self) -> Result<(), BootInfoError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::VersionMismatch {
+                expected: BOOT_INFO_VERSION,
+                found: self.version,
+            });
+        }
+        let size = core::mem::size_of::<BootInfo>() as u32;
+        if self.size != size {
+            return Err(BootInfoError::SizeMismatch {
+                expected: size,
+                found: self.size,
+            });
+        }
+        Ok(())
+    }
+}
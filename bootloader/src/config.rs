@@ -0,0 +1,140 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use log::warn;
+
+pub const CONFIG_PATH: &str = "\\rikan.cfg";
+
+// Loader settings read from CONFIG_PATH on the boot volume.
+//
+// The file is a list of `key = value` lines. Empty lines and lines starting
+// with `#` are ignored, e.g.
+//
+//   kernel = \kernel.elf
+//   entry = \kernel-old.elf
+//   timeout = 5
+//   resolution = 1280x800
+//   dump_mmap = true
+//   bootlog = true
+//   serial_log = true
+//   kaslr = true
+//   verify_kernel = true
+//   cmdline = loglevel=debug noapic
+//   module = \initrd.tar
+//   module = \fonts\unifont.psf
+//
+// `module` may be given several times; each file is loaded next to the kernel.
+// `entry` may also be repeated to list the kernels offered in the boot menu.
+// Without any `entry`, every `kernel*.elf` in the root directory of any volume
+// is offered. Kernels and modules are looked up on the loader's volume first,
+// then on every other FAT volume.
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    pub kernel_path: String,
+    pub entries: Vec<String>,
+    pub timeout: usize,
+    pub resolution: Option<(usize, usize)>,
+    pub dump_mmap: bool,
+    pub bootlog: bool,
+    pub serial_log: bool,
+    pub kaslr: bool,
+    pub verify_kernel: bool,
+    pub cmdline: String,
+    pub modules: Vec<String>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            kernel_path: String::from("\\kernel.elf"),
+            entries: Vec::new(),
+            timeout: 5,
+            resolution: None,
+            dump_mmap: true,
+            bootlog: false,
+            serial_log: true,
+            kaslr: true,
+            verify_kernel: false,
+            cmdline: String::new(),
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl BootConfig {
+    pub fn parse(text: &str) -> Self {
+        let mut config = BootConfig::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    warn!("rikan.cfg:{}: expected `key = value`", i + 1);
+                    continue;
+                }
+            };
+
+            match key {
+                "kernel" => config.kernel_path = to_uefi_path(value),
+                "entry" => config.entries.push(to_uefi_path(value)),
+                "timeout" => match value.parse() {
+                    Ok(timeout) => config.timeout = timeout,
+                    Err(_) => warn!("rikan.cfg:{}: invalid timeout `{}`", i + 1, value),
+                },
+                "resolution" => match parse_resolution(value) {
+                    Some(resolution) => config.resolution = Some(resolution),
+                    None => warn!("rikan.cfg:{}: invalid resolution `{}`", i + 1, value),
+                },
+                "dump_mmap" => set_bool(&mut config.dump_mmap, value, i + 1),
+                "bootlog" => set_bool(&mut config.bootlog, value, i + 1),
+                "serial_log" => set_bool(&mut config.serial_log, value, i + 1),
+                "kaslr" => set_bool(&mut config.kaslr, value, i + 1),
+                "verify_kernel" => set_bool(&mut config.verify_kernel, value, i + 1),
+                "cmdline" => config.cmdline = value.to_string(),
+                "module" => config.modules.push(to_uefi_path(value)),
+                _ => warn!("rikan.cfg:{}: unknown key `{}`", i + 1, key),
+            }
+        }
+
+        config
+    }
+}
+
+// Accept both `/` and `\` as separators and make the path absolute
+fn to_uefi_path(path: &str) -> String {
+    let path = path.replace('/', "\\");
+    if path.starts_with('\\') {
+        path
+    } else {
+        format!("\\{}", path)
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once(['x', 'X'])?;
+    let width = width.trim().parse().ok()?;
+    let height = height.trim().parse().ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Sets a boolean key, keeping its current value if `value` is not a boolean
+fn set_bool(key: &mut bool, value: &str, line: usize) {
+    match parse_bool(value) {
+        Some(value) => *key = value,
+        None => warn!("rikan.cfg:{}: invalid boolean `{}`", line, value),
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::string::String;
+use core::fmt;
+use uefi::Status;
+
+// The loader stage that was running when booting failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step {
+    OpenRootDir,
+    ReadConfig,
+    SaveMemoryMap,
+    OpenKernel,
+    ReadKernel,
+    VerifyKernel,
+    ParseKernel,
+    ValidateKernel,
+    LoadKernel,
+    RelocateKernel,
+    LoadModules,
+    OpenGop,
+    SetUpPaging,
+    PrepareHandoff,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let step = match self {
+            Step::OpenRootDir => "opening the boot volume",
+            Step::ReadConfig => "reading the boot configuration",
+            Step::SaveMemoryMap => "saving the memory map",
+            Step::OpenKernel => "opening the kernel file",
+            Step::ReadKernel => "reading the kernel file",
+            Step::VerifyKernel => "verifying the kernel image",
+            Step::ParseKernel => "parsing the kernel ELF",
+            Step::ValidateKernel => "validating the kernel segments",
+            Step::RelocateKernel => "relocating the kernel",
+            Step::LoadKernel => "loading the kernel segments",
+            Step::LoadModules => "loading the boot modules",
+            Step::OpenGop => "setting up the graphics output",
+            Step::SetUpPaging => "building the kernel page tables",
+            Step::PrepareHandoff => "preparing the kernel handoff",
+        };
+        f.write_str(step)
+    }
+}
+
+#[derive(Debug)]
+pub struct LoaderError {
+    pub step: Step,
+    pub status: Status,
+    pub detail: Option<String>,
+}
+
+impl LoaderError {
+    pub fn new(step: Step, status: Status) -> Self {
+        LoaderError {
+            step,
+            status,
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: String) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed while {}: {:?}", self.step, self.status)?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
+    }
+}
+
+// Tags a UEFI error with the loader step it happened in
+pub trait ResultExt<T> {
+    fn at(self, step: Step) -> Result<T, LoaderError>;
+}
+
+impl<T, D: fmt::Debug> ResultExt<T> for uefi::Result<T, D> {
+    fn at(self, step: Step) -> Result<T, LoaderError> {
+        self.map_err(|e| LoaderError::new(step, e.status()))
+    }
+}
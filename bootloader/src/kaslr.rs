@@ -0,0 +1,74 @@
+use crate::paging::LARGE_PAGE_SIZE;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use uefi::prelude::*;
+use uefi::proto::rng::Rng;
+
+// The kernel base is slid by a multiple of LARGE_PAGE_SIZE within this window
+// above KERNEL_VIRTUAL_BASE, which stays clear of the kernel stack
+pub const KASLR_WINDOW: u64 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    Rng,
+    Rdrand,
+    Tsc,
+}
+
+impl fmt::Display for EntropySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EntropySource::Rng => "EFI_RNG_PROTOCOL",
+            EntropySource::Rdrand => "RDRAND",
+            EntropySource::Tsc => "TSC",
+        };
+        f.write_str(name)
+    }
+}
+
+fn rng_u64(bs: &BootServices) -> Option<u64> {
+    let handle = bs.get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = bs.open_protocol_exclusive::<Rng>(handle).ok()?;
+    let mut buf = [0; 8];
+    rng.get_rng(None, &mut buf).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+fn rdrand_u64() -> Option<u64> {
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    if __cpuid(1).ecx & (1 << 30) == 0 {
+        return None;
+    }
+    // RDRAND may transiently fail, the recommendation is to retry 10 times
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok);
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Best available random number and where it came from
+pub fn random_u64(bs: &BootServices) -> (u64, EntropySource) {
+    if let Some(value) = rng_u64(bs) {
+        return (value, EntropySource::Rng);
+    }
+    if let Some(value) = rdrand_u64() {
+        return (value, EntropySource::Rdrand);
+    }
+    (unsafe { _rdtsc() }, EntropySource::Tsc)
+}
+
+// Random, large page aligned offset for an image of `size` bytes that keeps
+// it inside KASLR_WINDOW
+pub fn random_slide(bs: &BootServices, size: u64) -> (u64, EntropySource) {
+    let (value, source) = random_u64(bs);
+    let slots = KASLR_WINDOW.saturating_sub(size) / LARGE_PAGE_SIZE + 1;
+    ((value % slots) * LARGE_PAGE_SIZE, source)
+}
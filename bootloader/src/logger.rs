@@ -0,0 +1,131 @@
+use alloc::string::String;
+use core::fmt::Write;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+use log::{LevelFilter, Log, Metadata, Record};
+use uefi::prelude::*;
+use uefi::proto::console::serial::Serial;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+pub const BOOT_LOG_PATH: &str = "\\bootlog.txt";
+
+static LOGGER: Logger = Logger;
+static mut STATE: LoggerState = LoggerState {
+    system_table: None,
+    serial: None,
+    buffer: String::new(),
+    keep_buffer: true,
+    save_buffer: false,
+};
+
+// Writes log records to the firmware text console, mirrors them to the first
+// serial port and keeps a copy in memory for BOOT_LOG_PATH.
+//
+// The loader is single threaded, so the state is used without locking.
+// Everything stops when `disable` is called before exiting boot services.
+pub struct Logger;
+
+struct LoggerState {
+    system_table: Option<SystemTable<Boot>>,
+    serial: Option<NonNull<Serial>>,
+    buffer: String,
+    // Records are buffered until the configuration says whether to save them
+    keep_buffer: bool,
+    save_buffer: bool,
+}
+
+fn state() -> &'static mut LoggerState {
+    unsafe { &mut *addr_of_mut!(STATE) }
+}
+
+impl LoggerState {
+    fn write_str(&mut self, s: &str) {
+        if let Some(system_table) = self.system_table.as_mut() {
+            let _ = system_table.stdout().write_str(s);
+        }
+        if let Some(mut serial) = self.serial {
+            // Serial terminals expect CRLF line endings
+            let serial = unsafe { serial.as_mut() };
+            for (i, line) in s.split('\n').enumerate() {
+                if i > 0 {
+                    let _ = serial.write(b"\r\n");
+                }
+                let _ = serial.write(line.as_bytes());
+            }
+        }
+        if self.keep_buffer {
+            self.buffer.push_str(s);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut line = String::new();
+        let _ = writeln!(line, "[{:>5}]: {}", record.level(), record.args());
+        state().write_str(&line);
+    }
+
+    fn flush(&self) {}
+}
+
+// Opens the first serial port without taking it away from the firmware,
+// which may be using it as a console too
+fn open_serial(bs: &BootServices) -> Option<NonNull<Serial>> {
+    let handle = bs.get_handle_for_protocol::<Serial>().ok()?;
+    let mut serial = unsafe {
+        bs.open_protocol::<Serial>(
+            OpenProtocolParams {
+                handle,
+                agent: bs.image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+    let ptr = NonNull::from(&mut *serial);
+    // Stay open for as long as the loader runs
+    core::mem::forget(serial);
+    Some(ptr)
+}
+
+pub fn init(system_table: &SystemTable<Boot>) {
+    let state = state();
+    state.serial = open_serial(system_table.boot_services());
+    state.system_table = Some(unsafe { system_table.unsafe_clone() });
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+// Stops mirroring records to the serial port
+pub fn disable_serial() {
+    state().serial = None;
+}
+
+// Chooses whether the records are saved to BOOT_LOG_PATH
+pub fn set_boot_log(enabled: bool) {
+    let state = state();
+    state.save_buffer = enabled;
+    if !enabled {
+        state.keep_buffer = false;
+        state.buffer = String::new();
+    }
+}
+
+// Records to save to BOOT_LOG_PATH, if the boot log is enabled
+pub fn boot_log() -> Option<&'static str> {
+    let state = unsafe { &*addr_of!(STATE) };
+    state.save_buffer.then_some(state.buffer.as_str())
+}
+
+pub fn disable() {
+    let state = state();
+    state.system_table = None;
+    state.serial = None;
+    state.keep_buffer = false;
+}
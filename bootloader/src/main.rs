@@ -3,38 +3,349 @@
 
 #[macro_use]
 extern crate alloc;
+
+mod config;
+mod error;
+mod kaslr;
+mod logger;
+mod menu;
+mod paging;
+mod reloc;
+mod sha256;
+mod timing;
+mod validate;
+
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
+use bootinfo::{
+    BootInfo, BootModule, BootModules, BootTimestamps, FrameBufferConfig, KernelSymbols,
+    MemoryMapConfig, BOOT_MODULE_NAME_LEN,
+};
+use config::{BootConfig, CONFIG_PATH};
+use core::fmt::Write;
 use core::result::Result;
 use elf_rs::*;
-use log::info;
-use uefi::proto::console::gop::{GraphicsOutput, ModeInfo};
+use error::{LoaderError, ResultExt, Step};
+use log::{info, warn};
+use logger::BOOT_LOG_PATH;
+use paging::{
+    kernel_virt_to_phys, PageTableBuilder, KERNEL_STACK_PAGES, KERNEL_STACK_TOP,
+    KERNEL_VIRTUAL_BASE, LARGE_PAGE_SIZE, MIN_IDENTITY_MAP_END, PAGE_SIZE,
+};
+use uefi::proto::console::gop::{GraphicsOutput, Mode, ModeInfo, PixelFormat};
+use uefi::proto::console::text::Color;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::loaded_image::LoadedImage;
 use uefi::proto::media::file::{
-    Directory, File, FileAttribute, FileInfo, FileMode, FileType::Regular, RegularFile,
+    Directory, File, FileAttribute, FileHandle, FileInfo, FileMode, RegularFile,
 };
-use uefi::table::boot::{AllocateType, MemoryMap, MemoryType, ScopedProtocol};
-use uefi::{prelude::*, Error};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::{
+    AllocateType, MemoryDescriptor, MemoryMap, MemoryType, ScopedProtocol, SearchType,
+};
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID};
+use uefi::{prelude::*, CStr16, Error};
 
 // set the memory allocator
 #[global_allocator]
 static ALLOCATOR: uefi::allocator::Allocator = uefi::allocator::Allocator;
 // end of setting the memory allocator
 
-const BASE_ADDR: u64 = 0x100000;
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-struct FrameBufferConfig {
-    pub frame_buffer: u64,
-    pub frame_buffer_size: u64,
-    pub mode_info: ModeInfo,
-}
-
 fn open_root_dir(bs: &BootServices, handle: Handle) -> Result<Directory, Error> {
     let mut sfs = bs.get_image_file_system(handle)?;
     let root = sfs.open_volume()?;
     Ok(root)
 }
 
+// Root directories of every FAT volume, starting with the loader's own.
+// Volumes that cannot be opened are skipped.
+fn open_volumes(bs: &BootServices, handle: Handle) -> Result<Vec<Directory>, Error> {
+    let mut volumes = vec![open_root_dir(bs, handle)?];
+
+    // The handle get_image_file_system opened, to skip it below
+    let image_volume = {
+        let loaded_image = bs.open_protocol_exclusive::<LoadedImage>(handle)?;
+        let device = loaded_image
+            .device()
+            .ok_or(Error::from(Status::UNSUPPORTED))?;
+        let device_path = bs.open_protocol_exclusive::<DevicePath>(device)?;
+        bs.locate_device_path::<SimpleFileSystem>(&mut &*device_path)?
+    };
+
+    let handles = bs.locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>())?;
+    for &volume in handles.iter().filter(|&&h| h != image_volume) {
+        match bs
+            .open_protocol_exclusive::<SimpleFileSystem>(volume)
+            .and_then(|mut sfs| sfs.open_volume())
+        {
+            Ok(root) => volumes.push(root),
+            Err(e) => warn!("Skipping a volume that cannot be opened: {:?}", e.status()),
+        }
+    }
+    info!("Found {} volume(s)", volumes.len());
+    Ok(volumes)
+}
+
+// Opens `path` on the first volume that has it as a regular file and returns
+// the index of that volume with the file
+fn find_file(volumes: &mut [Directory], path: &str) -> Option<(usize, RegularFile)> {
+    volumes.iter_mut().enumerate().find_map(|(i, root)| {
+        let file = open_file(root, path, FileMode::Read).ok()?;
+        Some((i, file.into_regular_file()?))
+    })
+}
+
+// Opens `path` relative to `root`. `path` uses UEFI separators, e.g. `\kernel.elf`.
+fn open_file(root: &mut Directory, path: &str, mode: FileMode) -> Result<FileHandle, Error> {
+    let mut path_buf = [0u16; 256];
+    let path = CStr16::from_str_with_buf(path, &mut path_buf)
+        .map_err(|_| Error::from(Status::INVALID_PARAMETER))?;
+    root.open(path, mode, FileAttribute::empty())
+}
+
+fn get_file_size(file: &mut RegularFile) -> Result<u64, Error> {
+    let mut file_info_buf: Vec<u8> = Vec::new();
+    let info_size = file
+        .get_info::<FileInfo>(&mut file_info_buf)
+        .err() // This should fail because the buffer is too small
+        .and_then(|e| *e.data())
+        .ok_or(Error::from(Status::BUFFER_TOO_SMALL))?;
+    file_info_buf.resize(info_size, 0);
+    let info = file
+        .get_info::<FileInfo>(&mut file_info_buf)
+        .map_err(|e| e.to_err_without_payload())?;
+    Ok(info.file_size())
+}
+
+// Reads a small file, such as a configuration file, into memory and closes it
+fn read_small_file(mut file: RegularFile) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; get_file_size(&mut file)? as usize];
+    let read_size = file
+        .read(&mut buf)
+        .map_err(|e| e.to_err_without_payload())?;
+    file.close();
+    buf.truncate(read_size);
+    Ok(buf)
+}
+
+// Reads the whole file into newly allocated LOADER_DATA pages
+fn read_file_to_pages(
+    bs: &BootServices,
+    file: &mut RegularFile,
+) -> Result<&'static mut [u8], Error> {
+    let file_size = get_file_size(file)? as usize;
+    let num_pages = file_size.div_ceil(0x1000).max(1);
+    let buffer = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, num_pages)?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, file_size) };
+
+    let read_size = file.read(buffer).map_err(|e| e.to_err_without_payload())?;
+    if read_size != file_size {
+        return Err(Status::END_OF_FILE.into());
+    }
+    Ok(buffer)
+}
+
+// Loads every module file listed in the configuration and returns the table
+// handed to the kernel
+fn load_modules(
+    bs: &BootServices,
+    volumes: &mut [Directory],
+    paths: &[String],
+) -> Result<BootModules, LoaderError> {
+    let mut modules: Vec<BootModule> = Vec::new();
+    for path in paths {
+        let in_module =
+            |e: Error| LoaderError::new(Step::LoadModules, e.status()).with_detail(path.clone());
+        // The kernel finds modules by name, so names must not be cut short
+        let path_name = path.trim_start_matches('\\').as_bytes();
+        if path_name.len() >= BOOT_MODULE_NAME_LEN {
+            let detail = format!(
+                "{}: module names are limited to {} bytes",
+                path,
+                BOOT_MODULE_NAME_LEN - 1
+            );
+            return Err(
+                LoaderError::new(Step::LoadModules, Status::INVALID_PARAMETER).with_detail(detail),
+            );
+        }
+        let (_, mut file) = find_file(volumes, path).ok_or(in_module(Status::NOT_FOUND.into()))?;
+        let data = read_file_to_pages(bs, &mut file).map_err(in_module)?;
+        file.close();
+
+        let mut name = [0u8; BOOT_MODULE_NAME_LEN];
+        name[..path_name.len()].copy_from_slice(path_name);
+
+        info!(
+            "Loaded module {} at 0x{:x} ({} bytes)",
+            path,
+            data.as_ptr() as u64,
+            data.len()
+        );
+        modules.push(BootModule {
+            name,
+            address: data.as_ptr() as u64,
+            size: data.len() as u64,
+        });
+    }
+
+    // The table is read by the kernel, so it must never be freed
+    let modules = modules.leak();
+    Ok(BootModules {
+        modules: modules.as_ptr() as u64,
+        count: modules.len() as u64,
+    })
+}
+
+fn read_config(root: &mut Directory) -> Result<BootConfig, LoaderError> {
+    let file = match open_file(root, CONFIG_PATH, FileMode::Read)
+        .ok()
+        .and_then(|file| file.into_regular_file())
+    {
+        Some(file) => file,
+        None => {
+            info!("No {} found, using the default configuration", CONFIG_PATH);
+            return Ok(BootConfig::default());
+        }
+    };
+
+    let buf = read_small_file(file).at(Step::ReadConfig)?;
+
+    match core::str::from_utf8(&buf) {
+        Ok(text) => Ok(BootConfig::parse(text)),
+        Err(_) => {
+            warn!(
+                "{} is not valid UTF-8, using the default configuration",
+                CONFIG_PATH
+            );
+            Ok(BootConfig::default())
+        }
+    }
+}
+
+// Digest of the kernel embedded at build time with
+// `RIKAN_KERNEL_SHA256=<hex digest> cargo build`
+const EMBEDDED_KERNEL_DIGEST: Option<&str> = option_env!("RIKAN_KERNEL_SHA256");
+
+// Expected kernel digest, from the loader itself or from `<kernel>.sha256`
+fn read_kernel_digest(
+    root: &mut Directory,
+    kernel_path: &str,
+) -> Result<Option<[u8; sha256::DIGEST_LEN]>, LoaderError> {
+    if let Some(hex) = EMBEDDED_KERNEL_DIGEST {
+        return sha256::parse_hex(hex).map(Some).ok_or_else(|| {
+            LoaderError::new(Step::VerifyKernel, Status::INVALID_PARAMETER)
+                .with_detail(String::from("the embedded kernel digest is malformed"))
+        });
+    }
+
+    let digest_path = format!("{}.sha256", kernel_path);
+    let file = match open_file(root, &digest_path, FileMode::Read)
+        .ok()
+        .and_then(|file| file.into_regular_file())
+    {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let buf = read_small_file(file).at(Step::VerifyKernel)?;
+
+    core::str::from_utf8(&buf)
+        .ok()
+        .and_then(sha256::parse_hex)
+        .map(Some)
+        .ok_or_else(|| {
+            LoaderError::new(Step::VerifyKernel, Status::INVALID_PARAMETER)
+                .with_detail(format!("{} does not hold a SHA-256 digest", digest_path))
+        })
+}
+
+fn verify_kernel(
+    root: &mut Directory,
+    config: &BootConfig,
+    kernel_buffer: &[u8],
+) -> Result<(), LoaderError> {
+    let expected = match read_kernel_digest(root, &config.kernel_path)? {
+        Some(expected) => expected,
+        None if config.verify_kernel => {
+            return Err(
+                LoaderError::new(Step::VerifyKernel, Status::SECURITY_VIOLATION)
+                    .with_detail(format!("no digest found for {}", config.kernel_path)),
+            )
+        }
+        None => {
+            warn!(
+                "No digest for {}, skipping verification",
+                config.kernel_path
+            );
+            return Ok(());
+        }
+    };
+
+    let actual = sha256::digest(kernel_buffer);
+    if actual != expected {
+        return Err(
+            LoaderError::new(Step::VerifyKernel, Status::SECURITY_VIOLATION).with_detail(format!(
+                "SHA-256 mismatch: expected {}, got {}",
+                sha256::to_hex(&expected),
+                sha256::to_hex(&actual)
+            )),
+        );
+    }
+    info!("Kernel SHA-256 verified: {}", sha256::to_hex(&actual));
+    Ok(())
+}
+
+// End of the highest physical memory region the firmware reports
+fn max_physical_address(bs: &BootServices) -> Result<u64, Error> {
+    with_memory_map(bs, |mmap| {
+        mmap.entries()
+            .map(|desc| desc.phys_start + desc.page_count * PAGE_SIZE)
+            .max()
+            .unwrap_or(0)
+    })
+}
+
+// Allocates `num_pages` pages of free physical memory above 1 MiB, aligned to
+// a large page, and returns their start address. A candidate the firmware has
+// handed out since the memory map was read is skipped for the next one.
+fn allocate_free_region(bs: &BootServices, num_pages: usize) -> Result<u64, Error> {
+    let size = num_pages as u64 * PAGE_SIZE;
+    let candidates: Vec<u64> = with_memory_map(bs, |mmap| {
+        mmap.entries()
+            .filter(|desc| desc.ty == MemoryType::CONVENTIONAL)
+            .filter_map(|desc| {
+                let start = desc
+                    .phys_start
+                    .max(0x10_0000)
+                    .next_multiple_of(LARGE_PAGE_SIZE);
+                let end = desc.phys_start + desc.page_count * PAGE_SIZE;
+                (start + size <= end).then_some(start)
+            })
+            .collect()
+    })?;
+    candidates
+        .into_iter()
+        .find(|&start| {
+            bs.allocate_pages(
+                AllocateType::Address(start),
+                MemoryType::LOADER_DATA,
+                num_pages,
+            )
+            .is_ok()
+        })
+        .ok_or_else(|| Status::OUT_OF_RESOURCES.into())
+}
+
+// Reads the memory map into a buffer with room for a few more entries and
+// passes it to `f`
+fn with_memory_map<R>(bs: &BootServices, f: impl FnOnce(&MemoryMap) -> R) -> Result<R, Error> {
+    let mmap_size = bs.memory_map_size();
+    let mut mmap_buf = vec![0; mmap_size.map_size + 8 * mmap_size.entry_size];
+    let mmap = get_memory_map(bs, &mut mmap_buf)?;
+    Ok(f(&mmap))
+}
+
 fn get_memory_map<'a>(
     bs: &'a BootServices,
     mmap_buf: &'a mut [u8],
@@ -50,11 +361,11 @@ fn save_memory_map<'a>(
     file: &mut RegularFile,
     mmap_buf: &'a mut [u8],
 ) -> Result<MemoryMap<'a>, Error> {
-    let mmap = get_memory_map(bs, mmap_buf).unwrap();
+    let mmap = get_memory_map(bs, mmap_buf)?;
     let mmap_iter = mmap.entries();
 
     file.write("Index, Type, PhysicalStart, NumberOfPages, Attribute\n".as_bytes())
-        .unwrap();
+        .map_err(|e| e.to_err_without_payload())?;
 
     for (i, m) in mmap_iter.enumerate() {
         file.write(
@@ -64,58 +375,217 @@ fn save_memory_map<'a>(
             )
             .as_bytes(),
         )
-        .unwrap();
+        .map_err(|e| e.to_err_without_payload())?;
     }
     Ok(mmap)
 }
 
-fn open_gop(bs: &BootServices) -> Result<ScopedProtocol<GraphicsOutput>, Error> {
-    info!("Opening GOP...");
-    let gop_handle = if let Ok(gop_handle) = bs.get_handle_for_protocol::<GraphicsOutput>() {
-        info!("GOP handle obtained");
-        gop_handle
-    } else {
-        panic!("Failed to locate GOP handle");
+// Writes the collected loader log to BOOT_LOG_PATH, followed by `failure`
+fn save_boot_log(
+    bs: &BootServices,
+    handle: Handle,
+    failure: Option<&LoaderError>,
+) -> Result<(), Error> {
+    let Some(log) = logger::boot_log() else {
+        return Ok(());
     };
-    let gop = if let Ok(gop) = bs.open_protocol_exclusive::<GraphicsOutput>(gop_handle) {
-        gop
-    } else {
-        panic!("Failed to open GOP");
+
+    let mut root = open_root_dir(bs, handle)?;
+    // Start from an empty file, writing does not truncate
+    if let Ok(old) = open_file(&mut root, BOOT_LOG_PATH, FileMode::ReadWrite) {
+        old.delete()?;
+    }
+    let mut file = open_file(&mut root, BOOT_LOG_PATH, FileMode::CreateReadWrite)?
+        .into_regular_file()
+        .ok_or(Error::from(Status::INVALID_PARAMETER))?;
+
+    file.write(log.as_bytes())
+        .map_err(|e| e.to_err_without_payload())?;
+    if let Some(failure) = failure {
+        file.write(format!("Boot failed: {}\n", failure).as_bytes())
+            .map_err(|e| e.to_err_without_payload())?;
+    }
+    file.flush()?;
+    file.close();
+    Ok(())
+}
+
+// Formats the kernel can draw in. It writes 32-bit pixels, so bitmask modes
+// must use the top byte.
+fn is_supported_format(info: &ModeInfo) -> bool {
+    match (info.pixel_format(), info.pixel_bitmask()) {
+        (PixelFormat::Rgb | PixelFormat::Bgr, _) => true,
+        (PixelFormat::Bitmask, Some(mask)) => {
+            let bits = mask.red | mask.green | mask.blue | mask.reserved;
+            bits.leading_zeros() < 8
+        }
+        _ => false,
+    }
+}
+
+// Picks the mode matching `resolution` exactly, otherwise the largest mode that
+// fits in it. Without a requested resolution the largest mode is used.
+// Only modes the kernel can draw to are considered.
+fn select_gop_mode(
+    bs: &BootServices,
+    gop: &GraphicsOutput,
+    resolution: Option<(usize, usize)>,
+) -> Option<Mode> {
+    let area = |mode: &Mode| {
+        let (width, height) = mode.info().resolution();
+        width * height
     };
-    unsafe {
-        let handle_buffer = gop_handle.as_ptr() as *mut u8;
-        bs.free_pool(handle_buffer)?;
+
+    let mut best: Option<Mode> = None;
+    for mode in gop.modes(bs) {
+        let info = mode.info();
+        if !is_supported_format(info) {
+            continue;
+        }
+        let (width, height) = info.resolution();
+        if let Some((req_width, req_height)) = resolution {
+            if (width, height) == (req_width, req_height) {
+                return Some(mode);
+            }
+            if width > req_width || height > req_height {
+                continue;
+            }
+        }
+        if best.as_ref().is_none_or(|best| area(&mode) > area(best)) {
+            best = Some(mode);
+        }
+    }
+    best
+}
+
+fn open_gop(
+    bs: &BootServices,
+    resolution: Option<(usize, usize)>,
+) -> Result<ScopedProtocol<'_, GraphicsOutput>, Error> {
+    info!("Opening GOP...");
+    let gop_handle = bs.get_handle_for_protocol::<GraphicsOutput>()?;
+    info!("GOP handle obtained");
+    let mut gop = bs.open_protocol_exclusive::<GraphicsOutput>(gop_handle)?;
+
+    let mode = match select_gop_mode(bs, &gop, resolution) {
+        Some(mode) => mode,
+        None if resolution.is_some() => {
+            warn!("No video mode fits {:?}, trying any size", resolution);
+            select_gop_mode(bs, &gop, None).ok_or(Error::from(Status::UNSUPPORTED))?
+        }
+        None => return Err(Status::UNSUPPORTED.into()),
+    };
+    if *mode.info() != gop.current_mode_info() {
+        let (width, height) = mode.info().resolution();
+        info!("Switching to video mode {}x{}", width, height);
+        gop.set_mode(&mode)?;
     }
     Ok(gop)
 }
 
-fn load_kernel_file(bs: &BootServices, mut kernel_file_handle: RegularFile) -> Result<(), Error> {
-    let mut file_info_buf: Vec<u8> = Vec::new();
-    let info_size = kernel_file_handle
-        .get_info::<FileInfo>(&mut file_info_buf)
-        .unwrap_err() // This should fail because the buffer is too small
-        .data()
-        .unwrap();
-    file_info_buf.resize(info_size, 0);
-    let info = kernel_file_handle
-        .get_info::<FileInfo>(&mut file_info_buf)
-        .unwrap();
-    let kernel_file_size = info.file_size();
+// Records where the firmware put the ACPI and SMBIOS tables
+fn find_firmware_tables(system_table: &SystemTable<Boot>, boot_info: &mut BootInfo) {
+    let mut acpi1_rsdp = 0;
+    for entry in system_table.config_table() {
+        let address = entry.address as u64;
+        match entry.guid {
+            ACPI2_GUID => boot_info.acpi_rsdp = address,
+            ACPI_GUID => acpi1_rsdp = address,
+            SMBIOS_GUID => boot_info.smbios = address,
+            SMBIOS3_GUID => boot_info.smbios3 = address,
+            _ => {}
+        }
+    }
+    // Old firmware only provides the ACPI 1.0 RSDP
+    if boot_info.acpi_rsdp == 0 {
+        boot_info.acpi_rsdp = acpi1_rsdp;
+    }
+}
+
+// Where the kernel image ended up
+struct LoadedKernel {
+    entry_point: u64,
+    virtual_base: u64,
+    physical_base: u64,
+    size: u64,
+    slide: u64,
+    symbols: KernelSymbols,
+}
 
-    let kernel_buffer = bs
-        .allocate_pool(MemoryType::LOADER_DATA, kernel_file_size as usize)
-        .unwrap();
-    let kernel_buffer =
-        unsafe { core::slice::from_raw_parts_mut(kernel_buffer, kernel_file_size as usize) };
+// Keeps a copy of the symbol table and its string table for the kernel
+fn load_symbols(elf: &impl ElfFile) -> KernelSymbols {
+    let mut symbols = KernelSymbols {
+        symtab: 0,
+        symtab_size: 0,
+        strtab: 0,
+        strtab_size: 0,
+    };
 
-    kernel_file_handle.read(kernel_buffer).unwrap();
-    kernel_file_handle.close();
+    let Some(symtab) = elf
+        .section_header_iter()
+        .find(|sh| sh.sh_type() == SectionType::SHT_SYMTAB)
+    else {
+        warn!("The kernel has no symbol table");
+        return symbols;
+    };
+    let strtab = elf.section_header_nth(symtab.link() as usize);
+    let (Some(symtab), Some(strtab)) = (symtab.content(), strtab.and_then(|sh| sh.content()))
+    else {
+        warn!("The kernel symbol table is outside the file");
+        return symbols;
+    };
+
+    symbols.symtab = symtab.to_vec().leak().as_ptr() as u64;
+    symbols.symtab_size = symtab.len() as u64;
+    symbols.strtab = strtab.to_vec().leak().as_ptr() as u64;
+    symbols.strtab_size = strtab.len() as u64;
+    symbols
+}
+
+fn load_kernel_file(
+    bs: &BootServices,
+    kernel_buffer: &[u8],
+    kaslr: bool,
+) -> Result<LoadedKernel, LoaderError> {
+    let elf = match Elf::from_bytes(kernel_buffer) {
+        Ok(Elf::Elf64(elf)) => elf,
+        Ok(Elf::Elf32(_)) => {
+            return Err(LoaderError::new(Step::ParseKernel, Status::UNSUPPORTED)
+                .with_detail(String::from("32-bit ELF is not supported")))
+        }
+        Err(e) => {
+            return Err(LoaderError::new(Step::ParseKernel, Status::LOAD_ERROR)
+                .with_detail(format!("not a valid ELF file: {:?}", e)))
+        }
+    };
 
-    let elf = match Elf::from_bytes(kernel_buffer).unwrap() {
-        Elf::Elf64(elf) => elf,
-        Elf::Elf32(_) => panic!("32-bit ELF not supported"),
+    let relocatable = match elf.elf_header().elftype() {
+        ElfType::ET_EXEC => false,
+        ElfType::ET_DYN => true,
+        other => {
+            return Err(LoaderError::new(Step::ParseKernel, Status::UNSUPPORTED)
+                .with_detail(format!("unsupported ELF type {:?}", other)))
+        }
     };
 
+    validate::check_segments(&elf, kernel_buffer.len())?;
+
+    // The entry point has to be inside a loaded executable segment
+    let entry_point = elf.elf_header().entry_point();
+    let entry_in_text = elf.program_header_iter().any(|ph| {
+        ph.ph_type() == ProgramType::LOAD
+            && ph.flags().contains(ProgramHeaderFlags::EXECUTE)
+            && (ph.vaddr()..ph.vaddr() + ph.memsz()).contains(&entry_point)
+    });
+    if !entry_in_text {
+        return Err(
+            LoaderError::new(Step::ParseKernel, Status::LOAD_ERROR).with_detail(format!(
+                "entry point 0x{:x} is not inside an executable LOAD segment",
+                entry_point
+            )),
+        );
+    }
+
     let mut kernel_first = u64::max_value();
     let mut kernel_last = u64::min_value();
     for ph in elf.program_header_iter() {
@@ -129,16 +599,45 @@ fn load_kernel_file(bs: &BootServices, mut kernel_file_handle: RegularFile) -> R
     let kernel_first = kernel_first / 0x1000 * 0x1000; // Round down to the nearest page
     let num_pages = (kernel_last - kernel_first + 0xfff) as usize / 0x1000;
 
-    bs.allocate_pages(
-        AllocateType::Address(kernel_first),
-        MemoryType::LOADER_DATA,
-        num_pages,
-    )
-    .unwrap();
+    // A fixed-address kernel goes where it is linked, a relocatable one goes
+    // wherever there is room and runs from the start of the higher half. The
+    // room is allocated as soon as it is found, before anything else can
+    // allocate memory.
+    let (virtual_first, physical_first) = if relocatable {
+        let physical_first = allocate_free_region(bs, num_pages).at(Step::LoadKernel)?;
+        let mut virtual_first = KERNEL_VIRTUAL_BASE;
+        if kaslr {
+            let (slide, source) = kaslr::random_slide(bs, num_pages as u64 * PAGE_SIZE);
+            info!("KASLR: slide 0x{:x} from {}", slide, source);
+            virtual_first += slide;
+        }
+        (virtual_first, physical_first)
+    } else {
+        if kaslr {
+            warn!("KASLR disabled: the kernel is not relocatable");
+        }
+        (kernel_first, kernel_virt_to_phys(kernel_first))
+    };
+    let load_bias = virtual_first.wrapping_sub(kernel_first);
+
+    with_memory_map(bs, |mmap| {
+        validate::check_memory_map(&elf, kernel_first, physical_first, mmap)
+    })
+    .at(Step::ValidateKernel)??;
+
+    if !relocatable {
+        bs.allocate_pages(
+            AllocateType::Address(physical_first),
+            MemoryType::LOADER_DATA,
+            num_pages,
+        )
+        .at(Step::LoadKernel)?;
+    }
 
+    // Segments are copied through the loader's identity mapping
     for ph in elf.program_header_iter() {
         if ph.ph_type() == ProgramType::LOAD {
-            let start = ph.vaddr() as u64;
+            let start = physical_first + (ph.vaddr() - kernel_first);
             let offset = ph.offset() as usize;
             let size = ph.filesz() as usize;
             let buffer = &kernel_buffer[offset..offset + size];
@@ -157,74 +656,206 @@ fn load_kernel_file(bs: &BootServices, mut kernel_file_handle: RegularFile) -> R
         }
     }
 
-    Ok(())
+    if relocatable {
+        let image = unsafe {
+            core::slice::from_raw_parts_mut(
+                physical_first as *mut u8,
+                num_pages * PAGE_SIZE as usize,
+            )
+        };
+        let dynamic = elf
+            .program_header_iter()
+            .find(|ph| ph.ph_type() == ProgramType::DYNAMIC);
+        if let Some(dynamic) = dynamic {
+            let dynamic = dynamic.content().ok_or_else(|| {
+                LoaderError::new(Step::RelocateKernel, Status::LOAD_ERROR)
+                    .with_detail(String::from("dynamic segment is outside the file"))
+            })?;
+            let count = reloc::apply_relocations(image, kernel_first, load_bias, dynamic)?;
+            info!("Applied {} relocations", count);
+        }
+    }
+
+    Ok(LoadedKernel {
+        entry_point: entry_point.wrapping_add(load_bias),
+        virtual_base: virtual_first,
+        physical_base: physical_first,
+        size: num_pages as u64 * PAGE_SIZE,
+        slide: load_bias,
+        symbols: load_symbols(&elf),
+    })
 }
 
-#[entry]
-#[allow(dead_code)]
-#[allow(unreachable_code)]
-fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
-    uefi::helpers::init(&mut system_table).unwrap();
-    unsafe {
-        uefi::allocator::init(&mut system_table);
+// Identity maps physical memory and the frame buffer, maps the kernel at its
+// linked addresses and gives it a fresh stack
+fn build_page_tables(
+    bs: &BootServices,
+    kernel: &LoadedKernel,
+    frame_buffer: &FrameBufferConfig,
+) -> Result<u64, Error> {
+    let mut page_tables = PageTableBuilder::new(bs)?;
+
+    let identity_map_end = max_physical_address(bs)?.max(MIN_IDENTITY_MAP_END);
+    page_tables.identity_map(0, identity_map_end)?;
+    page_tables.identity_map(
+        frame_buffer.frame_buffer,
+        frame_buffer.frame_buffer + frame_buffer.frame_buffer_size,
+    )?;
+
+    if kernel.virtual_base != kernel.physical_base {
+        page_tables.map_range(kernel.virtual_base, kernel.physical_base, kernel.size)?;
     }
-    info!("Hello, UEFI!");
 
-    // Open the root directory
-    let bs = system_table.boot_services();
+    let stack_size = KERNEL_STACK_PAGES as u64 * PAGE_SIZE;
+    let stack = bs.allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        KERNEL_STACK_PAGES,
+    )?;
+    page_tables.map_range(KERNEL_STACK_TOP - stack_size, stack, stack_size)?;
+
+    Ok(page_tables.pml4_address())
+}
+
+// Everything needed to enter the kernel once boot services are gone
+struct Handoff {
+    entry_point: u64,
+    pml4: u64,
+    boot_info: &'static mut BootInfo,
+    descriptors: *mut MemoryDescriptor,
+    max_descriptors: usize,
+}
 
-    let mut root = if let Ok(root) = open_root_dir(bs, handle) {
-        root
+// Lets the user pick the kernel to boot when there is more than one
+fn select_kernel(
+    system_table: &mut SystemTable<Boot>,
+    volumes: &mut [Directory],
+    config: &mut BootConfig,
+) {
+    let entries = if config.entries.is_empty() {
+        let mut entries: Vec<String> = volumes.iter_mut().flat_map(menu::scan_kernels).collect();
+        entries.sort();
+        entries.dedup();
+        entries
     } else {
-        return Status::ABORTED;
+        config.entries.clone()
     };
+    if entries.len() < 2 || config.timeout == 0 {
+        return;
+    }
+    // The embedded digest pins the configured kernel, so no other can boot
+    if EMBEDDED_KERNEL_DIGEST.is_some() {
+        info!("Boot menu disabled: the loader only boots the kernel it was built for");
+        return;
+    }
+
+    // Start from the last kernel booted if it is still there
+    let last_choice = menu::last_choice(system_table.runtime_services());
+    let default = last_choice
+        .and_then(|last| entries.iter().position(|entry| *entry == last))
+        .or_else(|| {
+            entries
+                .iter()
+                .position(|entry| *entry == config.kernel_path)
+        })
+        .unwrap_or(0);
+
+    let selected = menu::choose(system_table, &entries, default, config.timeout);
+    config.kernel_path = entries[selected].clone();
+    menu::save_choice(system_table.runtime_services(), &config.kernel_path);
+    info!("Selected kernel: {}", config.kernel_path);
+}
+
+// Runs every loader step that needs boot services. Protocols and files opened
+// here are closed when it returns, as required before exiting boot services.
+fn prepare_boot(
+    handle: Handle,
+    system_table: &SystemTable<Boot>,
+    mut timestamps: BootTimestamps,
+) -> Result<Handoff, LoaderError> {
+    // Open the root directory
+    let bs = system_table.boot_services();
+
+    // The configuration, the mmap dump and the boot log live on the loader's
+    // own volume. Kernels and modules may be on any volume.
+    let mut volumes = open_volumes(bs, handle).at(Step::OpenRootDir)?;
+    let root = &mut volumes[0];
     // End of opening the root directory
 
+    // Read the boot configuration
+    let mut config = read_config(root)?;
+    info!("Boot configuration: {:?}", config);
+    logger::set_boot_log(config.bootlog);
+    if !config.serial_log {
+        logger::disable_serial();
+    }
+    // End of reading the boot configuration
+
+    // Show the boot menu. The console is only reachable through a mutable
+    // system table, which is borrowed by `bs` for the rest of this function.
+    let mut console = unsafe { system_table.unsafe_clone() };
+    select_kernel(&mut console, &mut volumes, &mut config);
+    timestamps.boot_menu = timing::rdtsc();
+    // End of showing the boot menu
+
     // Save the memory map to a file
-    let mmap_file_handle = root
-        .open(
-            cstr16!("mmap"),
-            FileMode::CreateReadWrite,
-            FileAttribute::empty(),
-        )
-        .unwrap();
-    let mmap_file_handle = mmap_file_handle.into_type().unwrap();
-
-    let mmap_buf = &mut vec![0; 4096 * 4];
-    let mmap_buf: &mut [u8] = &mut mmap_buf.as_mut_slice();
-    if let Regular(mut mmap_file_handle) = mmap_file_handle {
-        let _ = save_memory_map(bs, &mut mmap_file_handle, mmap_buf).unwrap();
-        mmap_file_handle.flush().unwrap();
+    if config.dump_mmap {
+        let mut mmap_file_handle = open_file(&mut volumes[0], "\\mmap", FileMode::CreateReadWrite)
+            .at(Step::SaveMemoryMap)?
+            .into_regular_file()
+            .ok_or(LoaderError::new(
+                Step::SaveMemoryMap,
+                Status::INVALID_PARAMETER,
+            ))?;
+
+        let mmap_buf = &mut vec![0; 4096 * 4];
+        let mmap_buf: &mut [u8] = mmap_buf.as_mut_slice();
+        let _ = save_memory_map(bs, &mut mmap_file_handle, mmap_buf).at(Step::SaveMemoryMap)?;
+        mmap_file_handle.flush().at(Step::SaveMemoryMap)?;
         mmap_file_handle.close();
-    } else {
-        info!("Failed to open mmap file");
-        return Status::ABORTED;
-    }
 
-    info!("Wrote memory map to mmap file");
+        info!("Wrote memory map to mmap file");
+    }
+    timestamps.mmap_dump = timing::rdtsc();
     // End of saving the memory map to a file
 
     // Load the kernel file
-    let kernel_file_handle = root
-        .open(
-            cstr16!("kernel.elf"),
-            FileMode::Read,
-            FileAttribute::empty(),
-        )
-        .unwrap();
-    let kernel_file_handle = kernel_file_handle.into_type().unwrap();
+    let (kernel_volume, mut kernel_file_handle) = find_file(&mut volumes, &config.kernel_path)
+        .ok_or(
+            LoaderError::new(Step::OpenKernel, Status::NOT_FOUND).with_detail(format!(
+                "{} is not on any of the {} volume(s)",
+                config.kernel_path,
+                volumes.len()
+            )),
+        )?;
+    info!("Found {} on volume {}", config.kernel_path, kernel_volume);
 
-    if let Regular(kernel_file_handle) = kernel_file_handle {
-        load_kernel_file(bs, kernel_file_handle).unwrap();
-        info!("Kernel file loaded");
-    } else {
-        info!("Failed to open kernel file");
-        return Status::ABORTED;
-    }
+    let kernel_buffer = read_file_to_pages(bs, &mut kernel_file_handle).at(Step::ReadKernel)?;
+    kernel_file_handle.close();
+    timestamps.kernel_read = timing::rdtsc();
+
+    verify_kernel(&mut volumes[kernel_volume], &config, kernel_buffer)?;
+    timestamps.kernel_verify = timing::rdtsc();
+
+    let kernel = load_kernel_file(bs, kernel_buffer, config.kaslr)?;
+    timestamps.kernel_load = timing::rdtsc();
+    info!(
+        "Kernel file loaded: 0x{:x} -> 0x{:x}, {} bytes",
+        kernel.virtual_base, kernel.physical_base, kernel.size
+    );
     //End of loading the kernel file
 
+    // Load the boot modules
+    let modules = load_modules(bs, &mut volumes, &config.modules)?;
+    // End of loading the boot modules
+
+    // The kernel expects a NUL-terminated command line
+    let mut cmdline = config.cmdline.clone().into_bytes();
+    cmdline.push(0);
+    let cmdline = cmdline.leak().as_ptr() as u64;
+
     //Open the GOP
-    let mut gop = open_gop(bs).unwrap();
+    let mut gop = open_gop(bs, config.resolution).at(Step::OpenGop)?;
     info!("GOP opened");
     let gop_frame_base = gop.frame_buffer().as_mut_ptr() as usize;
     let gop_frame_size = gop.frame_buffer().size() as usize;
@@ -235,30 +866,161 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         gop_frame_size
     );
     let mode_info = gop.current_mode_info();
+    info!(
+        "Pixel format: {:?}, bitmask: {:?}",
+        mode_info.pixel_format(),
+        mode_info.pixel_bitmask()
+    );
     let frame_buffer_config = FrameBufferConfig {
         frame_buffer: gop_frame_base as u64,
         frame_buffer_size: gop_frame_size as u64,
         mode_info,
     };
+    timestamps.gop_open = timing::rdtsc();
     // End of opening the GOP
 
-    // Jump to the kernel
-    info!("Jumping to the kernel...");
-    let entry_point_addr: usize = unsafe {
-        let addr_ptr = (BASE_ADDR + 24) as *const usize;
-        *addr_ptr
-    };
-    info!("Kernel entry point: 0x{:x}", entry_point_addr);
-    let entry_point_addr = entry_point_addr as *const ();
-    let entry_point = unsafe {
-        core::mem::transmute::<*const (), extern "efiapi" fn(&FrameBufferConfig) -> ()>(
-            entry_point_addr,
+    // Build the page tables the kernel runs on
+    let pml4 = build_page_tables(bs, &kernel, &frame_buffer_config).at(Step::SetUpPaging)?;
+    // End of building the page tables
+
+    // The memory map buffer has to be allocated while boot services are still
+    // available. Leave some room for the entries added by this allocation and
+    // by exit_boot_services itself.
+    let mmap_size = bs.memory_map_size();
+    let max_descriptors = mmap_size.map_size / mmap_size.entry_size + 8;
+    let descriptors = bs
+        .allocate_pool(
+            MemoryType::LOADER_DATA,
+            max_descriptors * core::mem::size_of::<MemoryDescriptor>(),
         )
+        .at(Step::PrepareHandoff)? as *mut MemoryDescriptor;
+
+    let memory_map_config = MemoryMapConfig {
+        descriptors: descriptors as u64,
+        map_size: 0, // filled in after exiting boot services
+        descriptor_size: core::mem::size_of::<MemoryDescriptor>() as u64,
+        descriptor_version: MemoryDescriptor::VERSION,
     };
+    // Boot services memory may be reused by the kernel, so keep the boot info
+    // in LOADER_DATA memory rather than on the loader stack
+    let boot_info = Box::leak(Box::new(BootInfo::new(
+        frame_buffer_config,
+        memory_map_config,
+    )));
+    boot_info.modules = modules;
+    boot_info.cmdline = cmdline;
+    boot_info.kernel_virtual_base = kernel.virtual_base;
+    boot_info.kernel_physical_base = kernel.physical_base;
+    boot_info.kernel_size = kernel.size;
+    boot_info.kernel_slide = kernel.slide;
+    boot_info.kernel_symbols = kernel.symbols;
+    boot_info.timestamps = timestamps;
+    boot_info.kernel_stack_top = KERNEL_STACK_TOP;
+    boot_info.kernel_stack_size = KERNEL_STACK_PAGES as u64 * PAGE_SIZE;
+    find_firmware_tables(system_table, boot_info);
+    info!(
+        "ACPI RSDP: 0x{:x}, SMBIOS: 0x{:x}, SMBIOS3: 0x{:x}",
+        boot_info.acpi_rsdp, boot_info.smbios, boot_info.smbios3
+    );
 
-    uefi::allocator::exit_boot_services(); // exit boot services before jumping to the kernel
+    Ok(Handoff {
+        entry_point: kernel.entry_point,
+        pml4,
+        boot_info,
+        descriptors,
+        max_descriptors,
+    })
+}
 
-    entry_point(&frame_buffer_config);
+// Shows the error on the UEFI console and waits for a key press so that the
+// message can be read before control goes back to the firmware
+fn report_error(system_table: &mut SystemTable<Boot>, error: &LoaderError) -> Status {
+    let stdout = system_table.stdout();
+    let _ = stdout.set_color(Color::LightRed, Color::Black);
+    let _ = write!(stdout, "\r\nBoot failed: {}\r\n", error);
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    let _ = write!(
+        stdout,
+        "Press any key to return to the firmware boot manager.\r\n"
+    );
 
-    Status::SUCCESS
+    let stdin = system_table.stdin();
+    let _ = stdin.reset(false);
+    if let Some(key_event) = stdin.wait_for_key_event() {
+        let _ = system_table
+            .boot_services()
+            .wait_for_event(&mut [key_event]);
+    }
+    let _ = system_table.stdin().read_key();
+
+    error.status
+}
+
+#[entry]
+#[allow(dead_code)]
+#[allow(unreachable_code)]
+fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    let mut timestamps = BootTimestamps {
+        loader_start: timing::rdtsc(),
+        ..Default::default()
+    };
+    // There is no console to report a failure on yet
+    if let Err(e) = uefi::helpers::init(&mut system_table) {
+        return e.status();
+    }
+    unsafe {
+        uefi::allocator::init(&mut system_table);
+    }
+    logger::init(&system_table);
+    timestamps.allocator_init = timing::rdtsc();
+    info!("Hello, UEFI!");
+    timestamps.tsc_frequency = timing::measure_tsc_frequency(system_table.boot_services());
+
+    let handoff = match prepare_boot(handle, &system_table, timestamps) {
+        Ok(handoff) => handoff,
+        Err(e) => {
+            if let Err(log_error) = save_boot_log(system_table.boot_services(), handle, Some(&e)) {
+                warn!(
+                    "Could not write {}: {:?}",
+                    BOOT_LOG_PATH,
+                    log_error.status()
+                );
+            }
+            return report_error(&mut system_table, &e);
+        }
+    };
+
+    // Jump to the kernel
+    info!("Jumping to the kernel...");
+    info!("Kernel entry point: 0x{:x}", handoff.entry_point);
+
+    if let Err(e) = save_boot_log(system_table.boot_services(), handle, None) {
+        warn!("Could not write {}: {:?}", BOOT_LOG_PATH, e.status());
+    }
+
+    // Boot services are gone after this, so nothing can be logged anymore
+    logger::disable();
+    // This also stops the allocator from using boot services
+    let (runtime_table, mmap) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
+    handoff.boot_info.timestamps.exit_boot_services = timing::rdtsc();
+    handoff.boot_info.uefi_system_table = runtime_table.as_ptr() as u64;
+
+    // Copy the final memory map so that the kernel sees a packed descriptor array
+    let mut num_descriptors = 0;
+    for (i, desc) in mmap.entries().take(handoff.max_descriptors).enumerate() {
+        unsafe { handoff.descriptors.add(i).write(*desc) };
+        num_descriptors += 1;
+    }
+    handoff.boot_info.memory_map.map_size =
+        (num_descriptors * core::mem::size_of::<MemoryDescriptor>()) as u64;
+
+    let boot_info = handoff.boot_info as *const BootInfo as u64;
+    unsafe {
+        paging::jump_to_kernel(
+            handoff.pml4,
+            KERNEL_STACK_TOP,
+            handoff.entry_point,
+            boot_info,
+        )
+    }
 }
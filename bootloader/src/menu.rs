@@ -0,0 +1,160 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use log::warn;
+use uefi::prelude::*;
+use uefi::proto::console::text::{Color, Key, ScanCode};
+use uefi::proto::media::file::Directory;
+use uefi::table::boot::{EventType, TimerTrigger, Tpl};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::{cstr16, guid, CStr16};
+
+// The path of the kernel booted last is kept in this variable
+const LAST_CHOICE_NAME: &CStr16 = cstr16!("RikanLastKernel");
+const RIKAN_VENDOR: VariableVendor = VariableVendor(guid!("6c1f0a3e-52b4-4c8e-9d27-0b5f3e8a41d6"));
+
+// One second in the 100ns units of the UEFI timer
+const SECOND: u64 = 10_000_000;
+
+#[repr(C, align(8))]
+struct DirEntryBuffer([u8; 1024]);
+
+// Lists `kernel*.elf` files in the root directory, sorted by name
+pub fn scan_kernels(root: &mut Directory) -> Vec<String> {
+    let mut kernels = Vec::new();
+    let mut buf = DirEntryBuffer([0; 1024]);
+    if root.reset_entry_readout().is_err() {
+        return kernels;
+    }
+    while let Ok(Some(info)) = root.read_entry(&mut buf.0) {
+        if info.is_directory() {
+            continue;
+        }
+        let name = info.file_name().to_string();
+        let lower = name.to_ascii_lowercase();
+        if lower.starts_with("kernel") && lower.ends_with(".elf") {
+            kernels.push(format!("\\{}", name));
+        }
+    }
+    kernels.sort();
+    kernels
+}
+
+pub fn last_choice(rt: &RuntimeServices) -> Option<String> {
+    let mut buf = [0; 256];
+    let (data, _) = rt
+        .get_variable(LAST_CHOICE_NAME, &RIKAN_VENDOR, &mut buf)
+        .ok()?;
+    core::str::from_utf8(data).ok().map(String::from)
+}
+
+pub fn save_choice(rt: &RuntimeServices, path: &str) {
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS;
+    if let Err(e) = rt.set_variable(LAST_CHOICE_NAME, &RIKAN_VENDOR, attributes, path.as_bytes()) {
+        warn!("Could not remember the boot choice: {:?}", e.status());
+    }
+}
+
+fn draw(
+    system_table: &mut SystemTable<Boot>,
+    entries: &[String],
+    selected: usize,
+    remaining: Option<usize>,
+) {
+    let stdout = system_table.stdout();
+    let _ = stdout.set_color(Color::White, Color::Black);
+    let _ = stdout.clear();
+    let _ = writeln!(stdout, "Rikan boot menu\n");
+    for (i, entry) in entries.iter().enumerate() {
+        if i == selected {
+            let _ = stdout.set_color(Color::Black, Color::LightGray);
+            let _ = writeln!(stdout, " > {} ", entry);
+            let _ = stdout.set_color(Color::White, Color::Black);
+        } else {
+            let _ = writeln!(stdout, "   {} ", entry);
+        }
+    }
+    let _ = writeln!(stdout);
+    match remaining {
+        Some(seconds) => {
+            let _ = writeln!(
+                stdout,
+                "Booting {} in {} s. Press any key to stop.",
+                entries[selected], seconds
+            );
+        }
+        None => {
+            let _ = writeln!(stdout, "Use Up/Down to choose and Enter to boot.");
+        }
+    }
+}
+
+// Shows `entries` and returns the index the user picked, or `default` once
+// `timeout` seconds pass without a key press
+pub fn choose(
+    system_table: &mut SystemTable<Boot>,
+    entries: &[String],
+    default: usize,
+    timeout: usize,
+) -> usize {
+    let mut selected = default;
+    let mut remaining = Some(timeout);
+
+    let key_event = match system_table.stdin().wait_for_key_event() {
+        Some(event) => event,
+        None => return default,
+    };
+    let bs = system_table.boot_services();
+    let timer = match unsafe { bs.create_event(EventType::TIMER, Tpl::CALLBACK, None, None) } {
+        Ok(timer) => timer,
+        Err(_) => return default,
+    };
+    if bs
+        .set_timer(&timer, TimerTrigger::Periodic(SECOND))
+        .is_err()
+    {
+        let _ = bs.close_event(timer);
+        return default;
+    }
+    let _ = system_table.stdout().enable_cursor(false);
+
+    loop {
+        draw(system_table, entries, selected, remaining);
+        if remaining == Some(0) {
+            break;
+        }
+
+        let mut events = unsafe { [key_event.unsafe_clone(), timer.unsafe_clone()] };
+        let index = match system_table.boot_services().wait_for_event(&mut events) {
+            Ok(index) => index,
+            Err(_) => break,
+        };
+        if index == 1 {
+            remaining = remaining.map(|seconds| seconds - 1);
+            continue;
+        }
+
+        // Any key stops the countdown
+        if remaining.take().is_some() {
+            let _ = system_table
+                .boot_services()
+                .set_timer(&timer, TimerTrigger::Cancel);
+        }
+        match system_table.stdin().read_key() {
+            Ok(Some(Key::Special(ScanCode::UP))) => selected = selected.saturating_sub(1),
+            Ok(Some(Key::Special(ScanCode::DOWN))) => {
+                selected = (selected + 1).min(entries.len() - 1)
+            }
+            Ok(Some(Key::Printable(c))) if u16::from(c) == u16::from(b'\r') => break,
+            _ => {}
+        }
+    }
+
+    let _ = system_table.boot_services().close_event(timer);
+    let stdout = system_table.stdout();
+    let _ = stdout.clear();
+    let _ = stdout.enable_cursor(true);
+    selected
+}
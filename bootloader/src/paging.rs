@@ -0,0 +1,144 @@
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+use uefi::{Error, Status};
+
+// The kernel is linked at KERNEL_VIRTUAL_BASE + its physical load address
+pub const KERNEL_VIRTUAL_BASE: u64 = 0xffff_ffff_8000_0000;
+pub const KERNEL_STACK_TOP: u64 = 0xffff_ffff_ff00_0000;
+pub const KERNEL_STACK_PAGES: usize = 64;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+// Identity map at least the 32-bit address space, which holds most MMIO
+pub const MIN_IDENTITY_MAP_END: u64 = 0x1_0000_0000;
+pub const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Higher-half kernels are loaded at their offset from KERNEL_VIRTUAL_BASE,
+// kernels linked at low addresses are loaded where they are linked.
+pub fn kernel_virt_to_phys(virt: u64) -> u64 {
+    if virt >= KERNEL_VIRTUAL_BASE {
+        virt - KERNEL_VIRTUAL_BASE
+    } else {
+        virt
+    }
+}
+
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [u64; 512],
+}
+
+fn table_index(virt: u64, level: usize) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+// Builds the 4-level page tables the kernel starts with. Tables are allocated
+// as LOADER_DATA pages so that they survive exit_boot_services.
+pub struct PageTableBuilder<'a> {
+    bs: &'a BootServices,
+    pml4: *mut PageTable,
+}
+
+impl<'a> PageTableBuilder<'a> {
+    pub fn new(bs: &'a BootServices) -> Result<Self, Error> {
+        let pml4 = Self::allocate_table(bs)?;
+        Ok(PageTableBuilder { bs, pml4 })
+    }
+
+    // Physical address to load into CR3
+    pub fn pml4_address(&self) -> u64 {
+        self.pml4 as u64
+    }
+
+    fn allocate_table(bs: &BootServices) -> Result<*mut PageTable, Error> {
+        let table = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)?
+            as *mut PageTable;
+        unsafe { core::ptr::write_bytes(table, 0, 1) };
+        Ok(table)
+    }
+
+    // Returns the table referenced by `table[index]`, creating it if needed
+    fn next_table(&self, table: *mut PageTable, index: usize) -> Result<*mut PageTable, Error> {
+        let entry = unsafe { &mut (*table).entries[index] };
+        if *entry & PRESENT != 0 {
+            if *entry & HUGE_PAGE != 0 {
+                // Already covered by a large page
+                return Err(Status::ALREADY_STARTED.into());
+            }
+            return Ok((*entry & ADDRESS_MASK) as *mut PageTable);
+        }
+        let next = Self::allocate_table(self.bs)?;
+        *entry = next as u64 | PRESENT | WRITABLE;
+        Ok(next)
+    }
+
+    // Returns the page directory covering `virt`
+    fn page_directory(&self, virt: u64) -> Result<*mut PageTable, Error> {
+        let pdpt = self.next_table(self.pml4, table_index(virt, 4))?;
+        self.next_table(pdpt, table_index(virt, 3))
+    }
+
+    pub fn map_page(&mut self, virt: u64, phys: u64) -> Result<(), Error> {
+        let pd = self.page_directory(virt)?;
+        let pt = self.next_table(pd, table_index(virt, 2))?;
+        unsafe { (*pt).entries[table_index(virt, 1)] = phys | PRESENT | WRITABLE };
+        Ok(())
+    }
+
+    fn map_large_page(&mut self, virt: u64, phys: u64) -> Result<(), Error> {
+        let pd = self.page_directory(virt)?;
+        let entry = unsafe { &mut (*pd).entries[table_index(virt, 2)] };
+        if *entry & PRESENT == 0 {
+            *entry = phys | PRESENT | WRITABLE | HUGE_PAGE;
+        }
+        Ok(())
+    }
+
+    // Maps [virt, virt + size) to [phys, phys + size) with 4 KiB pages
+    pub fn map_range(&mut self, virt: u64, phys: u64, size: u64) -> Result<(), Error> {
+        let num_pages = size.div_ceil(PAGE_SIZE);
+        for i in 0..num_pages {
+            self.map_page(virt + i * PAGE_SIZE, phys + i * PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    // Identity maps [start, end) with 2 MiB pages
+    pub fn identity_map(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        let mut addr = start / LARGE_PAGE_SIZE * LARGE_PAGE_SIZE;
+        while addr < end {
+            self.map_large_page(addr, addr)?;
+            addr += LARGE_PAGE_SIZE;
+        }
+        Ok(())
+    }
+}
+
+// Switches to the new page tables and stack and calls the kernel entry point
+// with `boot_info` as its only argument. Never returns.
+//
+// # Safety
+//
+// Boot services must have been exited, and the page tables must map the
+// currently running loader code, the kernel, the stack and `boot_info`.
+pub unsafe fn jump_to_kernel(pml4: u64, stack_top: u64, entry_point: u64, boot_info: u64) -> ! {
+    core::arch::asm!(
+        "mov cr3, {pml4}",
+        "mov rsp, {stack_top}",
+        "xor ebp, ebp",
+        // Shadow space required by the efiapi (Microsoft x64) calling convention
+        "sub rsp, 32",
+        "call {entry_point}",
+        "2:",
+        "hlt",
+        "jmp 2b",
+        pml4 = in(reg) pml4,
+        stack_top = in(reg) stack_top,
+        entry_point = in(reg) entry_point,
+        in("rcx") boot_info,
+        options(noreturn),
+    )
+}
@@ -0,0 +1,108 @@
+use crate::error::{LoaderError, Step};
+use alloc::format;
+use uefi::Status;
+
+// Dynamic section tags
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+// Relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const DYN_ENTRY_SIZE: usize = 16;
+const RELA_ENTRY_SIZE: usize = 24;
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+fn relocation_error(detail: alloc::string::String) -> LoaderError {
+    LoaderError::new(Step::RelocateKernel, Status::LOAD_ERROR).with_detail(detail)
+}
+
+// Applies the RELA relocations listed in `dynamic` to a loaded image.
+// `image` holds the image as linked at `link_base`, and `load_bias` is the
+// difference between the address the image runs at and the linked one.
+// Returns the number of relocations applied.
+pub fn apply_relocations(
+    image: &mut [u8],
+    link_base: u64,
+    load_bias: u64,
+    dynamic: &[u8],
+) -> Result<usize, LoaderError> {
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry_size = RELA_ENTRY_SIZE as u64;
+    for entry in dynamic.chunks_exact(DYN_ENTRY_SIZE) {
+        let tag = read_u64(entry, 0) as i64;
+        let value = read_u64(entry, 8);
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry_size = value,
+            _ => {}
+        }
+    }
+
+    let Some(rela) = rela else {
+        return Ok(0);
+    };
+    if rela_entry_size != RELA_ENTRY_SIZE as u64 {
+        return Err(relocation_error(format!(
+            "unexpected RELA entry size {}",
+            rela_entry_size
+        )));
+    }
+    if rela_size % RELA_ENTRY_SIZE as u64 != 0 {
+        return Err(relocation_error(format!(
+            "RELA table size {} is not a multiple of the entry size",
+            rela_size
+        )));
+    }
+
+    // The relocation table itself lives in a loaded segment
+    let table_start = rela.wrapping_sub(link_base) as usize;
+    let table_end = table_start.wrapping_add(rela_size as usize);
+    if table_start > table_end || table_end > image.len() {
+        return Err(relocation_error(format!(
+            "RELA table at 0x{:x} ({} bytes) is outside the kernel image",
+            rela, rela_size
+        )));
+    }
+
+    let mut count = 0;
+    for i in (table_start..table_end).step_by(RELA_ENTRY_SIZE) {
+        let offset = read_u64(image, i);
+        let info = read_u64(image, i + 8);
+        let addend = read_u64(image, i + 16);
+        match info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let target = offset.wrapping_sub(link_base) as usize;
+                if target.checked_add(8).is_none_or(|end| end > image.len()) {
+                    return Err(relocation_error(format!(
+                        "relocation target 0x{:x} is outside the kernel image",
+                        offset
+                    )));
+                }
+                let value = load_bias.wrapping_add(addend);
+                image[target..target + 8].copy_from_slice(&value.to_le_bytes());
+                count += 1;
+            }
+            other => {
+                return Err(relocation_error(format!(
+                    "unsupported relocation type {} at 0x{:x}",
+                    other, offset
+                )))
+            }
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,14 @@
+use core::arch::x86_64::_rdtsc;
+use uefi::table::boot::BootServices;
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// Counts TSC ticks over a 10 ms stall. Good enough to turn the boot stage
+// timestamps into milliseconds.
+pub fn measure_tsc_frequency(bs: &BootServices) -> u64 {
+    let start = rdtsc();
+    bs.stall(10_000);
+    (rdtsc() - start) * 100
+}
@@ -0,0 +1,127 @@
+use crate::error::{LoaderError, Step};
+use crate::paging::PAGE_SIZE;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use elf_rs::{ElfFile, ProgramType};
+use log::warn;
+use uefi::table::boot::{MemoryMap, MemoryType};
+use uefi::Status;
+
+// Memory the kernel must never be loaded over
+const PROTECTED_TYPES: [MemoryType; 9] = [
+    MemoryType::RESERVED,
+    MemoryType::RUNTIME_SERVICES_CODE,
+    MemoryType::RUNTIME_SERVICES_DATA,
+    MemoryType::UNUSABLE,
+    MemoryType::ACPI_RECLAIM,
+    MemoryType::ACPI_NON_VOLATILE,
+    MemoryType::MMIO,
+    MemoryType::MMIO_PORT_SPACE,
+    MemoryType::PAL_CODE,
+];
+
+// Turns the conflicts found into one error. Every conflict is logged, the
+// first one is reported.
+fn conflicts_to_result(conflicts: Vec<String>) -> Result<(), LoaderError> {
+    let Some(first) = conflicts.first() else {
+        return Ok(());
+    };
+    for conflict in &conflicts {
+        warn!("{}", conflict);
+    }
+    let detail = match conflicts.len() {
+        1 => first.clone(),
+        n => format!("{} (and {} more)", first, n - 1),
+    };
+    Err(LoaderError::new(Step::ValidateKernel, Status::LOAD_ERROR).with_detail(detail))
+}
+
+// Checks that every LOAD segment lies inside the file, fits in the address
+// space and does not overlap another LOAD segment
+pub fn check_segments(elf: &impl ElfFile, file_size: usize) -> Result<(), LoaderError> {
+    let mut conflicts = Vec::new();
+    let mut ranges: Vec<(usize, u64, u64)> = Vec::new();
+
+    for (i, ph) in elf.program_header_iter().enumerate() {
+        if ph.ph_type() != ProgramType::LOAD {
+            continue;
+        }
+        match ph.offset().checked_add(ph.filesz()) {
+            Some(end) if end <= file_size as u64 => {}
+            _ => conflicts.push(format!(
+                "segment {}: file range 0x{:x}+0x{:x} exceeds the file size 0x{:x}",
+                i,
+                ph.offset(),
+                ph.filesz(),
+                file_size
+            )),
+        }
+        if ph.filesz() > ph.memsz() {
+            conflicts.push(format!(
+                "segment {}: file size 0x{:x} is larger than its memory size 0x{:x}",
+                i,
+                ph.filesz(),
+                ph.memsz()
+            ));
+        }
+        match ph.vaddr().checked_add(ph.memsz()) {
+            Some(end) => ranges.push((i, ph.vaddr(), end)),
+            None => conflicts.push(format!(
+                "segment {}: 0x{:x}+0x{:x} wraps around the address space",
+                i,
+                ph.vaddr(),
+                ph.memsz()
+            )),
+        }
+    }
+
+    if ranges.is_empty() && conflicts.is_empty() {
+        conflicts.push(String::from("the kernel has no LOAD segments"));
+    }
+    for (n, &(i, start, end)) in ranges.iter().enumerate() {
+        for &(j, other_start, other_end) in &ranges[n + 1..] {
+            if start < other_end && other_start < end {
+                conflicts.push(format!(
+                    "segment {} (0x{:x}-0x{:x}) overlaps segment {} (0x{:x}-0x{:x})",
+                    i, start, end, j, other_start, other_end
+                ));
+            }
+        }
+    }
+
+    conflicts_to_result(conflicts)
+}
+
+// Checks that no LOAD segment, placed at `physical_first + (vaddr - kernel_first)`,
+// overlaps memory the firmware or the hardware owns
+pub fn check_memory_map(
+    elf: &impl ElfFile,
+    kernel_first: u64,
+    physical_first: u64,
+    mmap: &MemoryMap,
+) -> Result<(), LoaderError> {
+    let mut conflicts = Vec::new();
+
+    for (i, ph) in elf.program_header_iter().enumerate() {
+        if ph.ph_type() != ProgramType::LOAD || ph.memsz() == 0 {
+            continue;
+        }
+        let start = physical_first + (ph.vaddr() - kernel_first);
+        let end = start + ph.memsz();
+        for desc in mmap.entries() {
+            if !PROTECTED_TYPES.contains(&desc.ty) {
+                continue;
+            }
+            let region_end = desc.phys_start + desc.page_count * PAGE_SIZE;
+            if start < region_end && desc.phys_start < end {
+                conflicts.push(format!(
+                    "segment {} (0x{:x}-0x{:x}) overlaps {:?} region 0x{:x}-0x{:x}",
+                    i, start, end, desc.ty, desc.phys_start, region_end
+                ));
+            }
+        }
+    }
+
+    conflicts_to_result(conflicts)
+}
@@ -0,0 +1,22 @@
+use core::arch::asm;
+
+// Calls `f` with the return address of every frame on the rbp chain,
+// innermost first. This relies on the kernel being built with frame pointers
+// and on the loader entering it with rbp cleared.
+pub fn walk(max_depth: usize, mut f: impl FnMut(u64)) {
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+
+    for _ in 0..max_depth {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let return_address = unsafe { *frame.add(1) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+        rbp = unsafe { *frame };
+    }
+}
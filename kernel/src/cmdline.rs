@@ -0,0 +1,72 @@
+use core::mem::MaybeUninit;
+use core::str::FromStr;
+
+static mut CMDLINE: MaybeUninit<CommandLine> = MaybeUninit::uninit();
+static mut IS_INITIALIZED: bool = false;
+
+// Kernel command line passed by the loader, e.g.
+//
+//   loglevel=debug noapic test=graphics
+//
+// Arguments are separated by whitespace. `key=value` arguments are looked up
+// with `get`/`get_as`, bare words are boolean flags.
+#[derive(Debug, Copy, Clone)]
+pub struct CommandLine {
+    line: &'static str,
+}
+
+impl CommandLine {
+    pub fn new(line: &'static str) -> Self {
+        CommandLine { line }
+    }
+
+    pub fn initialize(line: &'static str) {
+        if unsafe { IS_INITIALIZED } {
+            panic!("CommandLine is already initialized");
+        }
+        unsafe { IS_INITIALIZED = true };
+        unsafe { core::ptr::write(CMDLINE.as_mut_ptr(), CommandLine::new(line)) };
+    }
+
+    pub fn instance() -> &'static CommandLine {
+        if !unsafe { IS_INITIALIZED } {
+            panic!("CommandLine is not initialized");
+        }
+        unsafe { &*CMDLINE.as_ptr() }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.line
+    }
+
+    // Yields (key, value) for `key=value` and (flag, None) for bare flags
+    pub fn args(&self) -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+        self.line
+            .split_whitespace()
+            .map(|arg| match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg, None),
+            })
+    }
+
+    // The last occurrence wins so that options can be overridden by appending
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.args()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key)?.parse().ok()
+    }
+
+    // True for a bare `name` or `name=true`/`name=1`/`name=yes`/`name=on`
+    pub fn flag(&self, name: &str) -> bool {
+        match self.args().filter(|(k, _)| *k == name).last() {
+            Some((_, None)) => true,
+            Some((_, Some(value))) => matches!(value, "true" | "1" | "yes" | "on"),
+            None => false,
+        }
+    }
+}
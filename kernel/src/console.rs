@@ -13,7 +13,7 @@ pub struct Console {
     pub n_rows: usize,
     pub n_cols: usize,
 
-    _g: Graphics,
+    _g: &'static Graphics,
     _fg_color: PixelColor,
     _bg_color: PixelColor,
     _buffer: [[char; 81]; 25],
@@ -26,7 +26,7 @@ impl Console {
     const N_ROWS: usize = 25;
     const N_COLS: usize = 80;
 
-    fn new(_g: Graphics, _fg_color: PixelColor, _bg_color: PixelColor) -> Self {
+    fn new(_g: &'static Graphics, _fg_color: PixelColor, _bg_color: PixelColor) -> Self {
         let mut _buffer = [[' '; 81]; 25];
         let mut _cursor_row = 0;
         let mut _cursor_col = 0;
@@ -44,7 +44,7 @@ impl Console {
         }
     }
 
-    pub fn initialize(g: Graphics, fg_color: PixelColor, bg_color: PixelColor) {
+    pub fn initialize(g: &'static Graphics, fg_color: PixelColor, bg_color: PixelColor) {
         if unsafe { IS_INITIALIZED } {
             panic!("Console is already initialized");
         }
@@ -52,6 +52,10 @@ impl Console {
         unsafe { core::ptr::write(CONSOLE.as_mut_ptr(), Console::new(g, fg_color, bg_color)) };
     }
 
+    pub fn is_initialized() -> bool {
+        unsafe { IS_INITIALIZED }
+    }
+
     pub fn instance() -> &'static mut Console {
         if !unsafe { IS_INITIALIZED } {
             panic!("Console is not initialized");
@@ -68,29 +72,16 @@ impl Console {
         if self._cursor_row < self.n_rows - 1 {
             self._cursor_row += 1;
         } else {
-            self.clear();
+            // Move the text up one line by moving the console's pixels rather
+            // than redrawing them, which is much cheaper with a back buffer
             for row in 1..self.n_rows {
-                for col in 0..self.n_cols + 1 {
-                    self._buffer[row - 1][col] = self._buffer[row][col];
-                    write_ascii(
-                        &self._g,
-                        col * 8,
-                        (row - 1) * 16,
-                        self._buffer[row - 1][col],
-                        &self._fg_color,
-                    );
-                }
-            }
-            for col in 0..self.n_cols + 1 {
-                self._buffer[self.n_rows - 1][col] = ' ';
-                write_ascii(
-                    &self._g,
-                    col * 8,
-                    (self.n_rows - 1) * 16,
-                    ' ',
-                    &self._fg_color,
-                );
+                self._buffer[row - 1] = self._buffer[row];
             }
+            self._buffer[self.n_rows - 1] = [' '; 81];
+            let (width, height) = (self.n_cols * 8, self.n_rows * 16);
+            self._g.copy_rect(0, 16, width, height - 16, 0, 0);
+            self._g
+                .fill_rect(0, height - 16, width, 16, &self._bg_color);
         }
     }
 
@@ -100,7 +91,7 @@ impl Console {
                 self.new_line();
             } else if self._cursor_col < self.n_cols - 1 {
                 write_ascii(
-                    &self._g,
+                    self._g,
                     self._cursor_col * 8,
                     self._cursor_row * 16,
                     c,
@@ -111,7 +102,7 @@ impl Console {
             } else {
                 self.new_line();
                 write_ascii(
-                    &self._g,
+                    self._g,
                     self._cursor_col * 8,
                     self._cursor_row * 16,
                     c,
@@ -127,6 +118,7 @@ impl Console {
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.put_string(s);
+        self._g.flush();
         Ok(())
     }
 }
@@ -0,0 +1,367 @@
+use crate::graphics::{Graphics, PixelColor};
+
+// Most vertices fill_polygon accepts. The scanline intersections are kept on
+// the stack so that drawing does not depend on the heap.
+pub const MAX_POLYGON_POINTS: usize = 64;
+
+// Largest radius the ellipse and circle functions accept; larger ones are
+// ignored. It keeps the exact inside test within i128.
+pub const MAX_RADIUS: usize = 1 << 30;
+
+type Point = (isize, isize);
+
+// 2D primitives. Coordinates are signed and may lie off screen; everything is
+// clipped to width() x height() before it reaches the frame buffer. Every
+// primitive draws each of its pixels once, so translucent colors blend evenly.
+impl Graphics {
+    // Writes a pixel if it is on screen
+    pub fn put_pixel(&self, x: isize, y: isize, color: &PixelColor) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width() && (y as usize) < self.height() {
+            self.write_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    // Fills x0..=x1 on row y
+    fn fill_span(&self, y: isize, x0: isize, x1: isize, color: &PixelColor) {
+        if y < 0 || y as usize >= self.height() {
+            return;
+        }
+        let (x0, x1) = (x0.min(x1).max(0), x0.max(x1).min(self.width() as isize - 1));
+        if x0 <= x1 {
+            self.fill_rect(x0 as usize, y as usize, (x1 - x0 + 1) as usize, 1, color);
+        }
+    }
+
+    pub fn fill_rectangle(
+        &self,
+        x: isize,
+        y: isize,
+        width: usize,
+        height: usize,
+        color: &PixelColor,
+    ) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        // Clip the top left corner here, fill_rect clips the rest
+        let (x0, y0) = (x.max(0), y.max(0));
+        let right = x.saturating_add_unsigned(width);
+        let bottom = y.saturating_add_unsigned(height);
+        if x0 < right && y0 < bottom {
+            let (width, height) = ((right - x0) as usize, (bottom - y0) as usize);
+            self.fill_rect(x0 as usize, y0 as usize, width, height, color);
+        }
+    }
+
+    pub fn draw_rectangle(
+        &self,
+        x: isize,
+        y: isize,
+        width: usize,
+        height: usize,
+        color: &PixelColor,
+    ) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let right = x.saturating_add_unsigned(width) - 1;
+        let bottom = y.saturating_add_unsigned(height) - 1;
+        self.fill_span(y, x, right, color);
+        if bottom != y {
+            self.fill_span(bottom, x, right, color);
+        }
+        for row in y.saturating_add(1).max(0)..bottom.min(self.height() as isize) {
+            self.put_pixel(x, row, color);
+            if right != x {
+                self.put_pixel(right, row, color);
+            }
+        }
+    }
+
+    // Bresenham's line algorithm, both end points included. The line is
+    // clipped to the screen first, so only pixels on screen are visited.
+    pub fn draw_line(&self, x0: isize, y0: isize, x1: isize, y1: isize, color: &PixelColor) {
+        self.line(x0, y0, x1, y1, true, color);
+    }
+
+    // Cohen-Sutherland line clipping. Returns the part of the line from p0 to
+    // p1 that is on screen and whether p1 was moved, or None when the line
+    // misses the screen.
+    fn clip_line(&self, p0: Point, p1: Point) -> Option<(Point, Point, bool)> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const TOP: u8 = 4;
+        const BOTTOM: u8 = 8;
+
+        if self.width() == 0 || self.height() == 0 {
+            return None;
+        }
+        let (right, bottom) = (self.width() as isize - 1, self.height() as isize - 1);
+        let outcode = |(x, y): Point| {
+            let horizontal = match x {
+                x if x < 0 => LEFT,
+                x if x > right => RIGHT,
+                _ => 0,
+            };
+            let vertical = match y {
+                y if y < 0 => TOP,
+                y if y > bottom => BOTTOM,
+                _ => 0,
+            };
+            horizontal | vertical
+        };
+
+        let (mut p0, mut p1, mut end_clipped) = (p0, p1, false);
+        loop {
+            let (c0, c1) = (outcode(p0), outcode(p1));
+            if c0 | c1 == 0 {
+                return Some((p0, p1, end_clipped));
+            }
+            if c0 & c1 != 0 {
+                return None;
+            }
+            // Move an end point that is off screen onto the edge it lies beyond
+            let code = if c0 != 0 { c0 } else { c1 };
+            let ((x0, y0), (x1, y1)) = (p0, p1);
+            let p = if code & LEFT != 0 {
+                (0, interpolate(y0, y1, x0, x1, 0))
+            } else if code & RIGHT != 0 {
+                (right, interpolate(y0, y1, x0, x1, right))
+            } else if code & TOP != 0 {
+                (interpolate(x0, x1, y0, y1, 0), 0)
+            } else {
+                (interpolate(x0, x1, y0, y1, bottom), bottom)
+            };
+            if c0 != 0 {
+                p0 = p;
+            } else {
+                p1 = p;
+                end_clipped = true;
+            }
+        }
+    }
+
+    fn line(
+        &self,
+        x0: isize,
+        y0: isize,
+        x1: isize,
+        y1: isize,
+        include_end: bool,
+        color: &PixelColor,
+    ) {
+        let Some(((x0, y0), (x1, y1), end_clipped)) = self.clip_line((x0, y0), (x1, y1)) else {
+            return;
+        };
+        // An end moved onto the screen edge is not shared with another line
+        let include_end = include_end || end_clipped;
+
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            if x == x1 && y == y1 {
+                if include_end {
+                    self.put_pixel(x, y, color);
+                }
+                break;
+            }
+            self.put_pixel(x, y, color);
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    pub fn draw_circle(&self, cx: isize, cy: isize, radius: usize, color: &PixelColor) {
+        self.draw_ellipse(cx, cy, radius, radius, color);
+    }
+
+    pub fn fill_circle(&self, cx: isize, cy: isize, radius: usize, color: &PixelColor) {
+        self.fill_ellipse(cx, cy, radius, radius, color);
+    }
+
+    pub fn draw_ellipse(&self, cx: isize, cy: isize, rx: usize, ry: usize, color: &PixelColor) {
+        self.ellipse(cy, rx, ry, |dy, inner, outer| {
+            let (left, right) = (cx.saturating_sub(outer), cx.saturating_add(outer));
+            for y in mirrored(cy, dy) {
+                if inner == 0 {
+                    self.fill_span(y, left, right, color);
+                } else {
+                    self.fill_span(y, left, cx.saturating_sub(inner), color);
+                    self.fill_span(y, cx.saturating_add(inner), right, color);
+                }
+            }
+        });
+    }
+
+    pub fn fill_ellipse(&self, cx: isize, cy: isize, rx: usize, ry: usize, color: &PixelColor) {
+        self.ellipse(cy, rx, ry, |dy, _, outer| {
+            let (left, right) = (cx.saturating_sub(outer), cx.saturating_add(outer));
+            for y in mirrored(cy, dy) {
+                self.fill_span(y, left, right, color);
+            }
+        });
+    }
+
+    // Walks the lower right quarter of an ellipse centred on the origin, one
+    // row at a time. Calls `row(dy, inner, outer)` for the dy in 0..=ry whose
+    // row cy + dy or cy - dy is on screen, where 0..=outer is the part of the
+    // row inside the ellipse and inner..=outer the part on its edge.
+    //
+    // A pixel is inside when its centre is inside the ellipse with radii
+    // rx + 1/2 and ry + 1/2. The comparison is done in doubled coordinates to
+    // stay in integers.
+    fn ellipse(&self, cy: isize, rx: usize, ry: usize, mut row: impl FnMut(isize, isize, isize)) {
+        if rx > MAX_RADIUS || ry > MAX_RADIUS {
+            return;
+        }
+        let (a, b) = (2 * rx as i128 + 1, 2 * ry as i128 + 1);
+        let (a2, b2) = (a * a, b * b);
+        // Largest x with b2 * (2x)^2 + a2 * (2dy)^2 <= a2 * b2, or -1 when
+        // row dy is outside the ellipse
+        let half_width = |dy: i128| {
+            let rest = b2 - 4 * dy * dy;
+            if rest < 0 {
+                return -1;
+            }
+            isqrt((a2 * rest / (4 * b2)) as u128) as i128
+        };
+
+        // Rows below the centre, then rows above it, that are on screen
+        let (cy, height, ry) = (cy as i128, self.height() as i128, ry as i128);
+        let (first, last) = [(-cy, height - 1 - cy), (cy - height + 1, cy)]
+            .into_iter()
+            .map(|(first, last)| (first.max(0), last.min(ry)))
+            .filter(|(first, last)| first <= last)
+            .fold((i128::MAX, i128::MIN), |(f, l), (first, last)| {
+                (f.min(first), l.max(last))
+            });
+
+        for dy in first..=last {
+            let outer = half_width(dy);
+            let inner = (half_width(dy + 1) + 1).min(outer);
+            row(dy as isize, inner as isize, outer as isize);
+        }
+    }
+
+    pub fn draw_polygon(&self, points: &[(isize, isize)], color: &PixelColor) {
+        match points {
+            [] => {}
+            [(x, y)] => self.put_pixel(*x, *y, color),
+            [(x0, y0), (x1, y1)] => self.draw_line(*x0, *y0, *x1, *y1, color),
+            _ => {
+                // Every edge leaves out its end, which the next edge starts at.
+                // Edges of zero length are skipped, and a polygon made only of
+                // those is a single pixel.
+                let mut drawn = false;
+                for (i, &(x0, y0)) in points.iter().enumerate() {
+                    let (x1, y1) = points[(i + 1) % points.len()];
+                    if (x0, y0) != (x1, y1) {
+                        self.line(x0, y0, x1, y1, false, color);
+                        drawn = true;
+                    }
+                }
+                if !drawn {
+                    self.put_pixel(points[0].0, points[0].1, color);
+                }
+            }
+        }
+    }
+
+    // Scanline fill with the even-odd rule. A pixel is filled when its centre
+    // is inside the polygon; centres exactly on an edge count as inside on
+    // the left side only, so adjacent polygons do not overlap. Polygons with
+    // more than MAX_POLYGON_POINTS vertices are ignored.
+    pub fn fill_polygon(&self, points: &[(isize, isize)], color: &PixelColor) {
+        if points.len() < 3 || points.len() > MAX_POLYGON_POINTS {
+            return;
+        }
+        let top = points.iter().map(|p| p.1).min().unwrap().max(0);
+        let bottom = points
+            .iter()
+            .map(|p| p.1)
+            .max()
+            .unwrap()
+            .min(self.height() as isize - 1);
+
+        let mut crossings = [0i128; MAX_POLYGON_POINTS];
+        for y in top..=bottom {
+            let mut n = 0;
+            for (i, &p0) in points.iter().enumerate() {
+                let p1 = points[(i + 1) % points.len()];
+                // Walk every edge downwards, so the result does not depend on
+                // the winding. Each edge covers the rows y0..y1, which counts
+                // a shared vertex once and skips horizontal edges.
+                let ((x0, y0), (x1, y1)) = if p0.1 <= p1.1 { (p0, p1) } else { (p1, p0) };
+                if y < y0 || y >= y1 {
+                    continue;
+                }
+                // The edge crosses the row's centre line at
+                //   x0 + (x1 - x0) * (y + 1/2 - y0) / (y1 - y0)
+                // and the first pixel whose centre is at or right of that is
+                // x0 + ceil(num / den), computed exactly
+                let (x0, y0, x1, y1, y) =
+                    (x0 as i128, y0 as i128, x1 as i128, y1 as i128, y as i128);
+                let num = (x1 - x0) * (2 * (y - y0) + 1) - (y1 - y0);
+                let den = 2 * (y1 - y0);
+                crossings[n] = x0 - (-num).div_euclid(den);
+                n += 1;
+            }
+            let crossings = &mut crossings[..n];
+            crossings.sort_unstable();
+            for pair in crossings.chunks_exact(2) {
+                // First and last pixel whose centre lies between the crossings
+                let clamp = |x: i128| x.clamp(isize::MIN as i128, isize::MAX as i128) as isize;
+                let x0 = clamp(pair[0]);
+                let x1 = clamp(pair[1] - 1);
+                if x0 <= x1 {
+                    self.fill_span(y, x0, x1, color);
+                }
+            }
+        }
+    }
+}
+
+// The rows dy below and above cy, or just cy when dy is 0
+fn mirrored(cy: isize, dy: isize) -> impl Iterator<Item = isize> {
+    let above = (dy != 0).then_some(cy.saturating_sub(dy));
+    core::iter::once(cy.saturating_add(dy)).chain(above)
+}
+
+// The value at `b` of the line through (b0, a0) and (b1, a1), rounded to the
+// nearest integer. `b` lies between b0 and b1, which differ. The product is
+// taken on u128 magnitudes, so it fits for any isize inputs.
+fn interpolate(a0: isize, a1: isize, b0: isize, b1: isize, b: isize) -> isize {
+    let da = a1 as i128 - a0 as i128;
+    let n = da.unsigned_abs() * (b as i128 - b0 as i128).unsigned_abs();
+    let d = (b1 as i128 - b0 as i128).unsigned_abs();
+    let (q, r) = (n / d, n % d);
+    let q = if r >= d - r { q + 1 } else { q };
+    (a0 as i128 + da.signum() * q as i128) as isize
+}
+
+// Integer square root, rounded down, by Newton's method
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    // Start above the root; every step then moves down towards it
+    let mut x = 1u128 << (128 - n.leading_zeros()).div_ceil(2);
+    loop {
+        let next = (x + n / x) / 2;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
@@ -1,74 +1,224 @@
-use uefi::proto::console::gop::ModeInfo;
+pub use bootinfo::FrameBufferConfig;
 
+extern crate alloc;
+
+use alloc::vec;
+use core::cell::Cell;
 use core::mem::MaybeUninit;
+use uefi::proto::console::gop::PixelFormat;
 
 static mut GRAPHICS: MaybeUninit<Graphics> = MaybeUninit::uninit();
 static mut IS_INITIALIZED: bool = false;
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct FrameBufferConfig {
-    pub frame_buffer: usize,
-    pub frame_buffer_size: usize,
-    pub mode_info: ModeInfo,
-}
-
+// An 8-bit RGB color with straight (not premultiplied) alpha. Alpha 255 is
+// opaque, 0 is fully transparent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct PixelColor {
     r: u8,
     g: u8,
     b: u8,
+    a: u8,
 }
 
 impl PixelColor {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
-        PixelColor { r, g, b }
+        PixelColor { r, g, b, a: 255 }
+    }
+
+    pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        PixelColor { r, g, b, a }
+    }
+
+    pub fn red(&self) -> u8 {
+        self.r
+    }
+
+    pub fn green(&self) -> u8 {
+        self.g
+    }
+
+    pub fn blue(&self) -> u8 {
+        self.b
+    }
+
+    pub fn alpha(&self) -> u8 {
+        self.a
+    }
+}
+
+// How a color is combined with the pixel already on screen
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlendMode {
+    // Mixes the color over the pixel by its alpha
+    SourceOver,
+    // Adds the color, scaled by its alpha, to the pixel
+    Additive,
+}
+
+impl BlendMode {
+    // Draws `src` over the opaque `dst` and returns the opaque result
+    pub fn blend(&self, src: &PixelColor, dst: &PixelColor) -> PixelColor {
+        let a = src.a as u32;
+        let channel = |s: u8, d: u8| -> u8 {
+            let (s, d) = (s as u32, d as u32);
+            match self {
+                BlendMode::SourceOver => ((s * a + d * (255 - a) + 127) / 255) as u8,
+                BlendMode::Additive => (d + (s * a + 127) / 255).min(255) as u8,
+            }
+        };
+        PixelColor::new(
+            channel(src.r, dst.r),
+            channel(src.g, dst.g),
+            channel(src.b, dst.b),
+        )
+    }
+}
+
+// Position and width of one color channel in a bitmask pixel
+#[derive(Debug, Copy, Clone)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+impl Channel {
+    // The mask is expected to be one contiguous run of bits
+    fn from_mask(mask: u32) -> Self {
+        if mask == 0 {
+            return Channel { shift: 0, bits: 0 };
+        }
+        let shift = mask.trailing_zeros();
+        Channel {
+            shift,
+            bits: (mask >> shift).trailing_ones(),
+        }
+    }
+
+    fn max(&self) -> u64 {
+        (u32::MAX >> (32 - self.bits)) as u64
+    }
+
+    // Scales an 8-bit value to the channel width, rounding to nearest
+    fn encode(&self, value: u8) -> u32 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let scaled = (value as u64 * self.max() + 127) / 255;
+        (scaled as u32) << self.shift
+    }
+
+    // Scales the channel in `pixel` back to 8 bits
+    fn decode(&self, pixel: u32) -> u8 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let value = (pixel >> self.shift) as u64 & self.max();
+        ((value * 255 + self.max() / 2) / self.max()) as u8
     }
 }
 
+// How a color is packed into a 32-bit frame buffer pixel
 #[derive(Debug, Copy, Clone)]
+enum PixelLayout {
+    Rgb,
+    Bgr,
+    Bitmask {
+        red: Channel,
+        green: Channel,
+        blue: Channel,
+    },
+}
+
+impl PixelLayout {
+    fn encode(&self, color: &PixelColor) -> u32 {
+        match self {
+            PixelLayout::Rgb => (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32,
+            PixelLayout::Bgr => (color.b as u32) << 16 | (color.g as u32) << 8 | color.r as u32,
+            PixelLayout::Bitmask { red, green, blue } => {
+                red.encode(color.r) | green.encode(color.g) | blue.encode(color.b)
+            }
+        }
+    }
+
+    // The opaque color of a frame buffer pixel
+    fn decode(&self, pixel: u32) -> PixelColor {
+        let byte = |shift: u32| (pixel >> shift) as u8;
+        match self {
+            PixelLayout::Rgb => PixelColor::new(byte(16), byte(8), byte(0)),
+            PixelLayout::Bgr => PixelColor::new(byte(0), byte(8), byte(16)),
+            PixelLayout::Bitmask { red, green, blue } => {
+                PixelColor::new(red.decode(pixel), green.decode(pixel), blue.decode(pixel))
+            }
+        }
+    }
+}
+
+// Area written to the back buffer since the last flush, as x0..x1, y0..y1
+#[derive(Debug, Copy, Clone)]
+struct DirtyRect {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+impl DirtyRect {
+    const EMPTY: DirtyRect = DirtyRect {
+        x0: usize::MAX,
+        y0: usize::MAX,
+        x1: 0,
+        y1: 0,
+    };
+
+    fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+
+    fn include(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.x0 = self.x0.min(x);
+        self.y0 = self.y0.min(y);
+        self.x1 = self.x1.max(x + width);
+        self.y1 = self.y1.max(y + height);
+    }
+}
+
+// Draws to the GOP frame buffer, or to a back buffer in memory once
+// `enable_back_buffer` is called. With a back buffer nothing reaches the
+// screen until `flush`/`flush_rect`.
+//
+// Colors are combined with the pixels underneath using the blend mode, which
+// is SourceOver unless `set_blend_mode` says otherwise. Translucent drawing
+// reads the pixels back, which is slow without a back buffer.
+#[derive(Debug)]
 #[allow(dead_code)]
 pub struct Graphics {
     _cfg: FrameBufferConfig,
-    _pixel_writer: unsafe fn(&FrameBufferConfig, usize, usize, &PixelColor) -> (),
+    _layout: PixelLayout,
+    // width() * height() pixels in frame buffer format, or null
+    _back_buffer: *mut u32,
+    _dirty: Cell<DirtyRect>,
+    _blend_mode: Cell<BlendMode>,
 }
 
 impl Graphics {
     fn new(cfg: FrameBufferConfig) -> Self {
-        unsafe fn write_pixel_rgb(
-            cfg: &FrameBufferConfig,
-            x: usize,
-            y: usize,
-            color: &PixelColor,
-        ) -> () {
-            let addr = cfg.frame_buffer + (y * cfg.mode_info.stride() + x) * 4;
-            core::ptr::write_volatile(
-                addr as *mut u32,
-                (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32,
-            );
-        }
-
-        unsafe fn write_pixel_bgr(
-            cfg: &FrameBufferConfig,
-            x: usize,
-            y: usize,
-            color: &PixelColor,
-        ) -> () {
-            let addr = cfg.frame_buffer + (y * cfg.mode_info.stride() + x) * 4;
-            core::ptr::write_volatile(
-                addr as *mut u32,
-                (color.b as u32) << 16 | (color.g as u32) << 8 | color.r as u32,
-            );
-        }
-
-        let pixel_writer = match cfg.mode_info.pixel_format() {
-            uefi::proto::console::gop::PixelFormat::Rgb => write_pixel_rgb,
-            uefi::proto::console::gop::PixelFormat::Bgr => write_pixel_bgr,
+        let layout = match (cfg.mode_info.pixel_format(), cfg.mode_info.pixel_bitmask()) {
+            (PixelFormat::Rgb, _) => PixelLayout::Rgb,
+            (PixelFormat::Bgr, _) => PixelLayout::Bgr,
+            (PixelFormat::Bitmask, Some(mask)) => PixelLayout::Bitmask {
+                red: Channel::from_mask(mask.red),
+                green: Channel::from_mask(mask.green),
+                blue: Channel::from_mask(mask.blue),
+            },
             _ => panic!("unsupported pixel format"),
         };
 
         Graphics {
             _cfg: cfg,
-            _pixel_writer: pixel_writer,
+            _layout: layout,
+            _back_buffer: core::ptr::null_mut(),
+            _dirty: Cell::new(DirtyRect::EMPTY),
+            _blend_mode: Cell::new(BlendMode::SourceOver),
         }
     }
 
@@ -87,18 +237,247 @@ impl Graphics {
         unsafe { &*GRAPHICS.as_ptr() }
     }
 
+    pub fn instance_mut() -> &'static mut Graphics {
+        if !unsafe { IS_INITIALIZED } {
+            panic!("Graphics is not initialized");
+        }
+        unsafe { &mut *GRAPHICS.as_mut_ptr() }
+    }
+
+    // Allocates the back buffer from the heap, starting from what is on
+    // screen now
+    pub fn enable_back_buffer(&mut self) {
+        if self.has_back_buffer() {
+            return;
+        }
+        let buffer = vec![0u32; self.width() * self.height()].leak();
+        self._back_buffer = buffer.as_mut_ptr();
+        for y in 0..self.height() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.frame_buffer_row(y),
+                    self.back_buffer_row(y),
+                    self.width(),
+                );
+            }
+        }
+    }
+
+    pub fn has_back_buffer(&self) -> bool {
+        !self._back_buffer.is_null()
+    }
+
+    fn stride(&self) -> usize {
+        self._cfg.mode_info.stride()
+    }
+
+    fn frame_buffer_row(&self, y: usize) -> *mut u32 {
+        (self._cfg.frame_buffer as usize + y * self.stride() * 4) as *mut u32
+    }
+
+    fn back_buffer_row(&self, y: usize) -> *mut u32 {
+        unsafe { self._back_buffer.add(y * self.width()) }
+    }
+
+    // The row drawing goes to: the back buffer if there is one
+    fn target_row(&self, y: usize) -> *mut u32 {
+        if self.has_back_buffer() {
+            self.back_buffer_row(y)
+        } else {
+            self.frame_buffer_row(y)
+        }
+    }
+
+    // Remembers that a rectangle of the back buffer needs flushing
+    fn mark_dirty(&self, x: usize, y: usize, width: usize, height: usize) {
+        if self.has_back_buffer() {
+            let mut dirty = self._dirty.get();
+            dirty.include(x, y, width, height);
+            self._dirty.set(dirty);
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        self._blend_mode.get()
+    }
+
+    pub fn set_blend_mode(&self, mode: BlendMode) {
+        self._blend_mode.set(mode);
+    }
+
+    // Converts a color to the frame buffer's native format, for `blit`. The
+    // alpha channel is dropped.
+    pub fn encode(&self, color: &PixelColor) -> u32 {
+        self._layout.encode(color)
+    }
+
+    // Whether drawing `color` overwrites the pixels without reading them
+    fn replaces(&self, color: &PixelColor) -> bool {
+        color.a == 255 && self.blend_mode() == BlendMode::SourceOver
+    }
+
+    // Whether drawing `color` leaves the pixels as they are
+    fn is_invisible(&self, color: &PixelColor) -> bool {
+        color.a == 0
+    }
+
+    // The native pixel that drawing `color` over `pixel` produces
+    fn blend_pixel(&self, pixel: u32, color: &PixelColor) -> u32 {
+        let dst = self._layout.decode(pixel);
+        self._layout.encode(&self.blend_mode().blend(color, &dst))
+    }
+
+    // Clips a rectangle to the screen. Returns its width and height, which
+    // are 0 when nothing is left.
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        if x >= self.width() || y >= self.height() {
+            return (0, 0);
+        }
+        (width.min(self.width() - x), height.min(self.height() - y))
+    }
+
+    // Writes a pixel; coordinates off screen are ignored
     pub fn write_pixel(&self, x: usize, y: usize, color: &PixelColor) -> () {
-        unsafe {
-            (self._pixel_writer)(&self._cfg, x, y, color);
+        if x >= self.width() || y >= self.height() || self.is_invisible(color) {
+            return;
+        }
+        if self.has_back_buffer() {
+            let target = unsafe { self.back_buffer_row(y).add(x) };
+            let pixel = if self.replaces(color) {
+                self._layout.encode(color)
+            } else {
+                self.blend_pixel(unsafe { target.read() }, color)
+            };
+            unsafe { target.write(pixel) };
+            self.mark_dirty(x, y, 1, 1);
+        } else {
+            let target = unsafe { self.frame_buffer_row(y).add(x) };
+            let pixel = if self.replaces(color) {
+                self._layout.encode(color)
+            } else {
+                self.blend_pixel(unsafe { core::ptr::read_volatile(target) }, color)
+            };
+            unsafe { core::ptr::write_volatile(target, pixel) };
+        }
+    }
+
+    // The color on screen at (x, y), or None off screen. Reads the back
+    // buffer if there is one, so it includes what has not been flushed yet.
+    pub fn read_pixel(&self, x: usize, y: usize) -> Option<PixelColor> {
+        if x >= self.width() || y >= self.height() {
+            return None;
         }
+        let pixel = if self.has_back_buffer() {
+            unsafe { self.back_buffer_row(y).add(x).read() }
+        } else {
+            unsafe { core::ptr::read_volatile(self.frame_buffer_row(y).add(x)) }
+        };
+        Some(self._layout.decode(pixel))
     }
 
     pub fn clear(&self, color: &PixelColor) -> () {
-        for y in 0..self.height() {
-            for x in 0..self.width() {
-                self.write_pixel(x, y, color);
+        self.fill_rect(0, 0, self.width(), self.height(), color);
+    }
+
+    // Fills a rectangle, clipped to the screen, one row at a time
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: &PixelColor) {
+        let (width, height) = self.clip(x, y, width, height);
+        if width == 0 || height == 0 || self.is_invisible(color) {
+            return;
+        }
+        let pixel = self._layout.encode(color);
+        for row in y..y + height {
+            let row =
+                unsafe { core::slice::from_raw_parts_mut(self.target_row(row).add(x), width) };
+            if self.replaces(color) {
+                row.fill(pixel);
+            } else {
+                row.iter_mut()
+                    .for_each(|p| *p = self.blend_pixel(*p, color));
+            }
+        }
+        self.mark_dirty(x, y, width, height);
+    }
+
+    // Copies a rectangle of the screen to (dst_x, dst_y). Both rectangles are
+    // clipped to the screen and may overlap. Pixels are copied as they are,
+    // without blending.
+    pub fn copy_rect(
+        &self,
+        src_x: usize,
+        src_y: usize,
+        width: usize,
+        height: usize,
+        dst_x: usize,
+        dst_y: usize,
+    ) {
+        let (width, height) = self.clip(src_x, src_y, width, height);
+        let (width, height) = self.clip(dst_x, dst_y, width, height);
+        if width == 0 || height == 0 {
+            return;
+        }
+        let copy_row = |row: usize| unsafe {
+            core::ptr::copy(
+                self.target_row(src_y + row).add(src_x),
+                self.target_row(dst_y + row).add(dst_x),
+                width,
+            );
+        };
+        // Copy the rows in the order that reads each one before it is
+        // overwritten
+        if dst_y <= src_y {
+            (0..height).for_each(copy_row);
+        } else {
+            (0..height).rev().for_each(copy_row);
+        }
+        self.mark_dirty(dst_x, dst_y, width, height);
+    }
+
+    // Draws `width` x `height` pixels, already converted with `encode` and
+    // stored row after row, at (x, y). Parts off screen are clipped. Pixels
+    // are copied as they are, without blending.
+    pub fn blit(&self, x: usize, y: usize, width: usize, height: usize, pixels: &[u32]) {
+        let height = height.min(pixels.len() / width.max(1));
+        let (clipped_width, clipped_height) = self.clip(x, y, width, height);
+        if clipped_width == 0 || clipped_height == 0 {
+            return;
+        }
+        for (row, src) in pixels.chunks_exact(width).take(clipped_height).enumerate() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    src.as_ptr(),
+                    self.target_row(y + row).add(x),
+                    clipped_width,
+                );
             }
         }
+        self.mark_dirty(x, y, clipped_width, clipped_height);
+    }
+
+    // Copies a rectangle of the back buffer to the screen
+    pub fn flush_rect(&self, x: usize, y: usize, width: usize, height: usize) {
+        if !self.has_back_buffer() || x >= self.width() || y >= self.height() {
+            return;
+        }
+        let width = width.min(self.width() - x);
+        let height = height.min(self.height() - y);
+        for row in y..y + height {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.back_buffer_row(row).add(x),
+                    self.frame_buffer_row(row).add(x),
+                    width,
+                );
+            }
+        }
+    }
+
+    // Copies everything drawn since the last flush to the screen
+    pub fn flush(&self) {
+        let dirty = self._dirty.replace(DirtyRect::EMPTY);
+        if !dirty.is_empty() {
+            self.flush_rect(dirty.x0, dirty.y0, dirty.x1 - dirty.x0, dirty.y1 - dirty.y0);
+        }
     }
 
     pub fn width(&self) -> usize {
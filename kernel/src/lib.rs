@@ -1,8 +1,15 @@
 #![no_std]
 pub mod ascii_font;
+pub mod backtrace;
+pub mod cmdline;
 pub mod console;
+pub mod draw;
 pub mod font;
 pub mod graphics;
+pub mod memory_map;
+pub mod symbols;
+pub mod timing;
+pub mod uefi_runtime;
 
 #[macro_export]
 macro_rules! print {
@@ -1,40 +1,150 @@
 #![no_std]
 #![no_main]
 
+use bootinfo::BootInfo;
 use core::arch::asm;
 use core::panic::PanicInfo;
+use kernel::backtrace;
+use kernel::cmdline::CommandLine;
 use kernel::console::Console;
-use kernel::graphics::{FrameBufferConfig, Graphics, PixelColor};
+use kernel::graphics::{Graphics, PixelColor};
+use kernel::memory_map::{find_free_region, runtime_entries, usable_pages, PAGE_SIZE};
+use kernel::symbols::SymbolTable;
+use kernel::timing::{print_boot_times, rdtsc};
+use kernel::uefi_runtime::UefiRuntime;
 use kernel::{print, println};
 
 // set the memory allocator
 use linked_list_allocator::LockedHeap;
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
+const HEAP_SIZE: usize = 64 * 1024 * 1024;
 // end of setting the memory allocator
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    // Nothing can be shown before the console is up
+    if Console::is_initialized() {
+        println!("{}", info);
+        println!("Backtrace:");
+        backtrace::walk(16, |addr| {
+            let symbol = if SymbolTable::is_initialized() {
+                SymbolTable::instance().lookup(addr)
+            } else {
+                None
+            };
+            match symbol {
+                Some((name, offset)) => println!("  0x{:016x} {}+0x{:x}", addr, name, offset),
+                None => println!("  0x{:016x} <unknown>", addr),
+            }
+        });
+    }
+    halt();
+}
+
+fn halt() -> ! {
+    unsafe {
+        loop {
+            asm!("hlt");
+        }
+    }
 }
 
 #[no_mangle]
-pub extern "efiapi" fn kernel_main(c: &FrameBufferConfig) -> () {
-    Graphics::initialize(*c);
+pub extern "efiapi" fn kernel_main(boot_info: &BootInfo) -> () {
+    let kernel_entry = rdtsc();
+
+    // Nothing in boot_info can be trusted if the loader speaks another
+    // version of the handoff, not even the frame buffer to report it on.
+    if boot_info.validate().is_err() {
+        halt();
+    }
+
+    CommandLine::initialize(boot_info.cmdline());
+    if boot_info.uefi_system_table != 0 {
+        UefiRuntime::initialize(boot_info.uefi_system_table);
+    }
+    SymbolTable::initialize(&boot_info.kernel_symbols, boot_info.kernel_slide);
+
+    // Put the heap in the first free region that is large enough. The loader
+    // identity maps physical memory, so the address can be used as is.
+    let heap = find_free_region(&boot_info.memory_map, HEAP_SIZE);
+    if let Some(heap) = heap {
+        unsafe { ALLOCATOR.lock().init(heap as *mut u8, HEAP_SIZE) };
+    }
+    // End of setting up the heap
+
+    Graphics::initialize(boot_info.frame_buffer);
+    // Draw to memory and flush to the screen, unless told otherwise
+    if heap.is_some() && !CommandLine::instance().flag("nobackbuffer") {
+        Graphics::instance_mut().enable_back_buffer();
+    }
     let g = Graphics::instance();
-    Console::initialize(*g, PixelColor::new(255, 255, 255), PixelColor::new(0, 0, 0));
+    let graphics_init = rdtsc();
+    Console::initialize(g, PixelColor::new(255, 255, 255), PixelColor::new(0, 0, 0));
 
     // Clear the screen
     g.clear(&PixelColor::new(0, 0, 0));
+    g.flush();
 
-    // Write a string to the screen
+    // Write a string to the screen. 30 lines scroll the 25 line console.
+    let hello_start = rdtsc();
     for i in 0..30 {
         println!("Hello, World! {}", i);
     }
-
-    unsafe {
-        loop {
-            asm!("hlt");
+    let hello_ticks = rdtsc() - hello_start;
+    println!(
+        "Printed 30 lines in {} us (back buffer: {})",
+        boot_info.timestamps.ticks_to_us(hello_ticks).unwrap_or(0),
+        if g.has_back_buffer() { "on" } else { "off" }
+    );
+    println!("Command line: {}", CommandLine::instance().as_str());
+    println!(
+        "Kernel: 0x{:x} -> 0x{:x} ({} KiB, slide 0x{:x}), stack top 0x{:x}",
+        boot_info.kernel_virtual_base,
+        boot_info.kernel_physical_base,
+        boot_info.kernel_size / 1024,
+        boot_info.kernel_slide,
+        boot_info.kernel_stack_top
+    );
+    let m = &boot_info.memory_map;
+    println!(
+        "Usable memory: {} MiB ({} descriptors)",
+        usable_pages(m) * PAGE_SIZE / 1024 / 1024,
+        m.len()
+    );
+    println!(
+        "ACPI RSDP: 0x{:x}, SMBIOS: 0x{:x}, SMBIOS3: 0x{:x}",
+        boot_info.acpi_rsdp, boot_info.smbios, boot_info.smbios3
+    );
+    println!("Kernel symbols: {}", SymbolTable::instance().len());
+    println!(
+        "UEFI runtime regions: {}",
+        runtime_entries(&boot_info.memory_map).count()
+    );
+    if UefiRuntime::is_initialized() {
+        match UefiRuntime::instance().get_time() {
+            Ok(time) => println!("RTC: {}", time),
+            Err(e) => println!("RTC: unavailable ({:?})", e.status()),
         }
     }
+    for module in boot_info.modules.as_slice() {
+        println!(
+            "Module {}: 0x{:x} ({} bytes)",
+            module.name(),
+            module.address,
+            module.size
+        );
+    }
+
+    print_boot_times(
+        &boot_info.timestamps,
+        &[
+            ("kernel entry", kernel_entry),
+            ("graphics init", graphics_init),
+            ("console output", rdtsc()),
+        ],
+    );
+
+    halt();
 }
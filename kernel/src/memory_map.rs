@@ -0,0 +1,35 @@
+pub use bootinfo::MemoryMapConfig;
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryType};
+
+pub const PAGE_SIZE: usize = 4096;
+
+// Boot services memory can be reused once the loader has exited boot services.
+pub fn is_usable(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+    )
+}
+
+pub fn usable_entries(m: &MemoryMapConfig) -> impl Iterator<Item = &MemoryDescriptor> {
+    m.entries().filter(|desc| is_usable(desc.ty))
+}
+
+// Start of the first free region of at least `size` bytes above 1 MiB
+pub fn find_free_region(m: &MemoryMapConfig, size: usize) -> Option<u64> {
+    m.entries()
+        .filter(|desc| desc.ty == MemoryType::CONVENTIONAL && desc.phys_start >= 0x10_0000)
+        .find(|desc| desc.page_count as usize * PAGE_SIZE >= size)
+        .map(|desc| desc.phys_start)
+}
+
+pub fn usable_pages(m: &MemoryMapConfig) -> usize {
+    usable_entries(m).map(|desc| desc.page_count as usize).sum()
+}
+
+// Regions the firmware keeps using after boot services have exited. They have
+// to be mapped for UEFI runtime services calls.
+pub fn runtime_entries(m: &MemoryMapConfig) -> impl Iterator<Item = &MemoryDescriptor> {
+    m.entries()
+        .filter(|desc| desc.att.contains(MemoryAttribute::RUNTIME))
+}
@@ -0,0 +1,80 @@
+pub use bootinfo::KernelSymbols;
+
+use core::mem::MaybeUninit;
+
+static mut SYMBOLS: MaybeUninit<SymbolTable> = MaybeUninit::uninit();
+static mut IS_INITIALIZED: bool = false;
+
+// Size of an Elf64_Sym entry
+const SYMBOL_SIZE: usize = 24;
+const STT_FUNC: u8 = 2;
+
+// The kernel's own ELF symbol table, handed over by the loader. Symbol
+// values are link-time addresses and `slide` converts them to runtime ones.
+#[derive(Debug, Copy, Clone)]
+pub struct SymbolTable {
+    symtab: &'static [u8],
+    strtab: &'static [u8],
+    slide: u64,
+}
+
+impl SymbolTable {
+    pub fn new(symbols: &KernelSymbols, slide: u64) -> Self {
+        let symtab = unsafe { &*(symbols.symtab() as *const [u8]) };
+        let strtab = unsafe { &*(symbols.strtab() as *const [u8]) };
+        SymbolTable {
+            symtab,
+            strtab,
+            slide,
+        }
+    }
+
+    pub fn initialize(symbols: &KernelSymbols, slide: u64) {
+        if unsafe { IS_INITIALIZED } {
+            panic!("SymbolTable is already initialized");
+        }
+        unsafe { IS_INITIALIZED = true };
+        unsafe { core::ptr::write(SYMBOLS.as_mut_ptr(), SymbolTable::new(symbols, slide)) };
+    }
+
+    pub fn is_initialized() -> bool {
+        unsafe { IS_INITIALIZED }
+    }
+
+    pub fn instance() -> &'static SymbolTable {
+        if !unsafe { IS_INITIALIZED } {
+            panic!("SymbolTable is not initialized");
+        }
+        unsafe { &*SYMBOLS.as_ptr() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symtab.len() / SYMBOL_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let name = self.strtab.get(offset..)?;
+        let end = name.iter().position(|&c| c == 0)?;
+        core::str::from_utf8(&name[..end]).ok()
+    }
+
+    // Returns the function containing the runtime address `addr` and the
+    // offset of `addr` from its start
+    pub fn lookup(&self, addr: u64) -> Option<(&'static str, u64)> {
+        let addr = addr.wrapping_sub(self.slide);
+        self.symtab.chunks_exact(SYMBOL_SIZE).find_map(|sym| {
+            let name = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+            let info = sym[4];
+            let value = u64::from_le_bytes(sym[8..16].try_into().unwrap());
+            let size = u64::from_le_bytes(sym[16..24].try_into().unwrap());
+            if info & 0xf != STT_FUNC || !(value..value + size).contains(&addr) {
+                return None;
+            }
+            Some((self.name(name)?, addr - value))
+        })
+    }
+}
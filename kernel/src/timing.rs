@@ -0,0 +1,38 @@
+pub use bootinfo::BootTimestamps;
+
+use crate::{print, println};
+use core::arch::x86_64::_rdtsc;
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// Prints how long each loader stage and each of the kernel's `milestones`
+// took, relative to the previous one, and the time since the loader started
+pub fn print_boot_times(timestamps: &BootTimestamps, milestones: &[(&str, u64)]) {
+    let stages = timestamps.stages();
+    let start = timestamps.loader_start;
+    let mut previous = start;
+
+    println!("Boot time breakdown:");
+    for &(name, time) in stages.iter().chain(milestones.iter()) {
+        // Stages the loader did not reach are left at 0
+        if time == 0 {
+            continue;
+        }
+        let step = time.wrapping_sub(previous);
+        let total = time.wrapping_sub(start);
+        match (timestamps.ticks_to_us(step), timestamps.ticks_to_us(total)) {
+            (Some(step), Some(total)) => println!(
+                "  {:<20} +{:>6}.{:03} ms  {:>6}.{:03} ms",
+                name,
+                step / 1000,
+                step % 1000,
+                total / 1000,
+                total % 1000
+            ),
+            _ => println!("  {:<20} +{:>12} ticks  {:>12} ticks", name, step, total),
+        }
+        previous = time;
+    }
+}
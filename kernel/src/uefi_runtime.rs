@@ -0,0 +1,98 @@
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use uefi::table::boot::MemoryDescriptor;
+use uefi::table::runtime::{ResetType, RuntimeServices, Time, VariableAttributes, VariableVendor};
+use uefi::table::{Runtime, SystemTable};
+use uefi::{CStr16, Status};
+
+static mut UEFI_RUNTIME: MaybeUninit<UefiRuntime> = MaybeUninit::uninit();
+static mut IS_INITIALIZED: bool = false;
+
+// Firmware runtime services kept alive by the loader.
+//
+// The loader identity maps all physical memory, so the services can be
+// called in physical mode until `set_virtual_address_map` moves them.
+pub struct UefiRuntime {
+    system_table: SystemTable<Runtime>,
+}
+
+impl UefiRuntime {
+    pub fn initialize(system_table: u64) {
+        if unsafe { IS_INITIALIZED } {
+            panic!("UefiRuntime is already initialized");
+        }
+        let system_table = unsafe { SystemTable::<Runtime>::from_ptr(system_table as *mut c_void) }
+            .expect("no UEFI system table");
+        unsafe { IS_INITIALIZED = true };
+        unsafe { core::ptr::write(UEFI_RUNTIME.as_mut_ptr(), UefiRuntime { system_table }) };
+    }
+
+    pub fn is_initialized() -> bool {
+        unsafe { IS_INITIALIZED }
+    }
+
+    pub fn instance() -> &'static mut UefiRuntime {
+        if !unsafe { IS_INITIALIZED } {
+            panic!("UefiRuntime is not initialized");
+        }
+        unsafe { &mut *UEFI_RUNTIME.as_mut_ptr() }
+    }
+
+    fn services(&self) -> &RuntimeServices {
+        unsafe { self.system_table.runtime_services() }
+    }
+
+    // Reads the real time clock
+    pub fn get_time(&self) -> uefi::Result<Time> {
+        self.services().get_time()
+    }
+
+    pub fn reboot(&self) -> ! {
+        self.services()
+            .reset(ResetType::COLD, Status::SUCCESS, None)
+    }
+
+    pub fn poweroff(&self) -> ! {
+        self.services()
+            .reset(ResetType::SHUTDOWN, Status::SUCCESS, None)
+    }
+
+    pub fn get_variable<'a>(
+        &self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        buf: &'a mut [u8],
+    ) -> uefi::Result<(&'a [u8], VariableAttributes)> {
+        self.services().get_variable(name, vendor, buf)
+    }
+
+    pub fn set_variable(
+        &self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> uefi::Result {
+        self.services().set_variable(name, vendor, attributes, data)
+    }
+
+    // Switches the firmware to virtual addressing. `map` holds the memory map
+    // with `virt_start` filled in for every runtime region, and `system_table`
+    // is the new virtual address of the system table.
+    //
+    // # Safety
+    //
+    // This can only be done once, and only after the kernel has mapped every
+    // runtime region at its new address.
+    pub unsafe fn set_virtual_address_map(
+        &mut self,
+        map: &mut [MemoryDescriptor],
+        system_table: u64,
+    ) -> uefi::Result {
+        // The table is consumed by the call, so hand it a second handle to it
+        let current = SystemTable::<Runtime>::from_ptr(self.system_table.as_ptr() as *mut c_void)
+            .expect("no UEFI system table");
+        self.system_table = current.set_virtual_address_map(map, system_table)?;
+        Ok(())
+    }
+}
@@ -9,4 +9,8 @@ fi
 mkdir -p "$MOUNT_POINT/EFI/BOOT"
 cp ./bootloader/target/x86_64-unknown-uefi/release/bootloader.efi "$MOUNT_POINT/EFI/BOOT/BOOTX64.EFI"
 cp ./kernel/kernel.elf "$MOUNT_POINT/kernel.elf"
+(cd ./kernel && sha256sum kernel.elf) > "$MOUNT_POINT/kernel.elf.sha256"
 
+if [ -f ./rikan.cfg ]; then
+    cp ./rikan.cfg "$MOUNT_POINT/rikan.cfg"
+fi